@@ -1,4 +1,5 @@
 [curved-pwm]
 device_name = "rust-wifi"
 wifi_ssid = ""
-wifi_psk = ""
\ No newline at end of file
+wifi_psk = ""
+input_port = 4210
\ No newline at end of file
@@ -0,0 +1,171 @@
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::sys::{
+    adc_atten_t_ADC_ATTEN_DB_12, adc_bitwidth_t_ADC_BITWIDTH_DEFAULT,
+    adc_cali_create_scheme_curve_fitting, adc_cali_curve_fitting_config_t,
+    adc_cali_delete_scheme_curve_fitting, adc_cali_handle_t, adc_cali_raw_to_voltage,
+    adc_channel_t, adc_oneshot_chan_cfg_t, adc_oneshot_config_channel, adc_oneshot_io_to_channel,
+    adc_oneshot_new_unit, adc_oneshot_read, adc_oneshot_unit_handle_t, adc_oneshot_unit_init_cfg_t,
+    adc_unit_t, adc_unit_t_ADC_UNIT_1, ESP_OK,
+};
+use log::{error, warn};
+
+use crate::{esp32, input::Source, sensor::Definition};
+
+/// Full scale of a 12 bit reading at 12dB attenuation, without calibration.
+const UNCALIBRATED_FULL_SCALE_MV: i32 = 3300;
+const MAX_RAW: i32 = 4095;
+
+struct Unit(adc_oneshot_unit_handle_t);
+
+unsafe impl Send for Unit {}
+
+/// ADC1 is shared by every channel, and reads of a unit must not overlap.
+static UNIT: Mutex<Option<Unit>> = Mutex::new(None);
+
+fn with_unit<T>(f: impl FnOnce(adc_oneshot_unit_handle_t) -> Result<T>) -> Result<T> {
+    let mut unit = UNIT.lock().unwrap();
+
+    if unit.is_none() {
+        let mut handle: adc_oneshot_unit_handle_t = std::ptr::null_mut();
+        let mut init_config: adc_oneshot_unit_init_cfg_t = Default::default();
+        init_config.unit_id = adc_unit_t_ADC_UNIT_1;
+
+        let res = unsafe { adc_oneshot_new_unit(&init_config, &mut handle) };
+        if res != ESP_OK {
+            error!("Failed to install ADC1: {}", esp32::esp_err_to_str(res));
+            return Err(anyhow!("Failed to install ADC1"));
+        }
+
+        *unit = Some(Unit(handle));
+    }
+
+    f(unit.as_ref().unwrap().0)
+}
+
+/// Prefix of the sensors of ADC pins, e.g. `adc:2`.
+pub const SENSOR_PREFIX: &str = "adc:";
+
+pub fn sensor_name(gpio: i32) -> String {
+    format!("{}{}", SENSOR_PREFIX, gpio)
+}
+
+/// Millivolts of an ADC1 pin as a sensor of the sensor service, which owns every ADC read.
+pub fn definition(gpio: i32, interval: u64) -> Definition {
+    Definition {
+        name: sensor_name(gpio),
+        interval,
+        filters: vec![],
+        open: Box::new(move || Ok(Box::new(new(gpio)?) as Box<dyn Source>)),
+    }
+}
+
+/// A oneshot channel of ADC1 at 12dB attenuation, which reads up to about 3.1V.
+pub struct AdcChannel {
+    gpio: i32,
+    channel: adc_channel_t,
+    cali: adc_cali_handle_t,
+}
+
+unsafe impl Send for AdcChannel {}
+
+pub fn new(gpio: i32) -> Result<AdcChannel> {
+    let mut unit_id: adc_unit_t = Default::default();
+    let mut channel: adc_channel_t = Default::default();
+
+    let res = unsafe { adc_oneshot_io_to_channel(gpio, &mut unit_id, &mut channel) };
+    if res != ESP_OK || unit_id != adc_unit_t_ADC_UNIT_1 {
+        return Err(anyhow!("GPIO{} is not an ADC1 pin", gpio));
+    }
+
+    with_unit(|handle| {
+        let mut channel_config: adc_oneshot_chan_cfg_t = Default::default();
+        channel_config.atten = adc_atten_t_ADC_ATTEN_DB_12;
+        channel_config.bitwidth = adc_bitwidth_t_ADC_BITWIDTH_DEFAULT;
+
+        let res = unsafe { adc_oneshot_config_channel(handle, channel, &channel_config) };
+        if res != ESP_OK {
+            error!(
+                "Failed to config ADC channel of GPIO{}: {}",
+                gpio,
+                esp32::esp_err_to_str(res)
+            );
+            return Err(anyhow!("Failed to config ADC channel"));
+        }
+        Ok(())
+    })?;
+
+    let mut cali: adc_cali_handle_t = std::ptr::null_mut();
+    let mut cali_config: adc_cali_curve_fitting_config_t = Default::default();
+    cali_config.unit_id = adc_unit_t_ADC_UNIT_1;
+    cali_config.chan = channel;
+    cali_config.atten = adc_atten_t_ADC_ATTEN_DB_12;
+    cali_config.bitwidth = adc_bitwidth_t_ADC_BITWIDTH_DEFAULT;
+
+    let res = unsafe { adc_cali_create_scheme_curve_fitting(&cali_config, &mut cali) };
+    if res != ESP_OK {
+        warn!(
+            "No ADC calibration for GPIO{}, millivolts are estimated: {}",
+            gpio,
+            esp32::esp_err_to_str(res)
+        );
+        cali = std::ptr::null_mut();
+    }
+
+    Ok(AdcChannel {
+        gpio,
+        channel,
+        cali,
+    })
+}
+
+impl AdcChannel {
+    pub fn read_raw(&self) -> Result<i32> {
+        with_unit(|handle| {
+            let mut raw = 0;
+            let res = unsafe { adc_oneshot_read(handle, self.channel, &mut raw) };
+            if res != ESP_OK {
+                return Err(anyhow!(
+                    "Failed to read ADC of GPIO{}: {}",
+                    self.gpio,
+                    esp32::esp_err_to_str(res)
+                ));
+            }
+            Ok(raw)
+        })
+    }
+
+    pub fn read_millivolts(&self) -> Result<i32> {
+        let raw = self.read_raw()?;
+
+        if self.cali.is_null() {
+            return Ok(raw * UNCALIBRATED_FULL_SCALE_MV / MAX_RAW);
+        }
+
+        let mut millivolts = 0;
+        let res = unsafe { adc_cali_raw_to_voltage(self.cali, raw, &mut millivolts) };
+        if res != ESP_OK {
+            return Err(anyhow!(
+                "Failed to calibrate ADC of GPIO{}: {}",
+                self.gpio,
+                esp32::esp_err_to_str(res)
+            ));
+        }
+        Ok(millivolts)
+    }
+}
+
+impl Drop for AdcChannel {
+    fn drop(&mut self) {
+        if !self.cali.is_null() {
+            unsafe { adc_cali_delete_scheme_curve_fitting(self.cali) };
+        }
+    }
+}
+
+impl Source for AdcChannel {
+    fn read(&mut self) -> Result<f32> {
+        Ok(self.read_millivolts()? as f32)
+    }
+}
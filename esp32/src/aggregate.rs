@@ -0,0 +1,329 @@
+use std::{collections::HashSet, time::Instant};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::{Source, SourceConfig},
+    pwm_loop::Inputs,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Function {
+    Max,
+    Min,
+    Avg,
+    /// average by `weights`
+    Weighted,
+}
+
+/// A virtual sensor over other sensors or remote values, e.g. the hottest spot of a case.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AggregateConfig {
+    pub function: Function,
+    /// sensors, the chip temperature or remote values
+    pub inputs: Vec<SourceConfig>,
+    /// of each input, by `weighted` only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weights: Vec<f32>,
+}
+
+impl AggregateConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.inputs.is_empty() {
+            return Err(anyhow!("aggregate needs inputs"));
+        }
+        for input in &self.inputs {
+            match input {
+                SourceConfig::Temperature
+                | SourceConfig::Sensor { .. }
+                | SourceConfig::Remote { .. } => input.validate()?,
+                _ => return Err(anyhow!("aggregate inputs are sensors or remote values")),
+            }
+        }
+        if self.function == Function::Weighted {
+            if self.weights.len() != self.inputs.len() {
+                return Err(anyhow!("aggregate needs a weight for each input"));
+            }
+            if self.weights.iter().any(|weight| *weight < 0.0) {
+                return Err(anyhow!("aggregate weights must not be negative"));
+            }
+            if self.weights.iter().sum::<f32>() <= 0.0 {
+                return Err(anyhow!("aggregate weights must not all be 0"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Aggregates of `(name, config)` reading each other in a loop, e.g. `["a", "b", "a"]`,
+/// which never has a reading.
+pub fn cycle<'a>(aggregates: &[(&'a str, &'a AggregateConfig)]) -> Option<Vec<&'a str>> {
+    fn visit<'a>(
+        name: &'a str,
+        aggregates: &[(&'a str, &'a AggregateConfig)],
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> Option<Vec<&'a str>> {
+        if let Some(start) = path.iter().position(|other| *other == name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(name);
+            return Some(cycle);
+        }
+        if done.contains(name) {
+            return None;
+        }
+        // not an aggregate
+        let config: &'a AggregateConfig = aggregates.iter().find(|(other, _)| *other == name)?.1;
+
+        path.push(name);
+        for input in &config.inputs {
+            if let SourceConfig::Sensor { name } = input {
+                if let Some(cycle) = visit(name, aggregates, path, done) {
+                    return Some(cycle);
+                }
+            }
+        }
+        path.pop();
+        done.insert(name);
+        None
+    }
+
+    let mut done = HashSet::new();
+    aggregates
+        .iter()
+        .find_map(|(name, _)| visit(name, aggregates, &mut vec![], &mut done))
+}
+
+/// Combines `(value, weight)` of the inputs with a reading, None without any.
+pub fn combine(function: Function, values: &[(f32, f32)]) -> Option<f32> {
+    let first = values.first()?.0;
+    Some(match function {
+        Function::Max => values.iter().fold(first, |max, (value, _)| max.max(*value)),
+        Function::Min => values.iter().fold(first, |min, (value, _)| min.min(*value)),
+        Function::Avg => values.iter().map(|(value, _)| value).sum::<f32>() / values.len() as f32,
+        Function::Weighted => {
+            // spread over the inputs with a reading
+            let total: f32 = values.iter().map(|(_, weight)| weight).sum();
+            if total <= 0.0 {
+                return None;
+            }
+            values
+                .iter()
+                .map(|(value, weight)| value * weight)
+                .sum::<f32>()
+                / total
+        }
+    })
+}
+
+pub struct Aggregate {
+    function: Function,
+    /// with the weight of each
+    sources: Vec<(Box<dyn Source>, f32)>,
+    /// of the first input read last time to expire
+    expires_at: Option<Instant>,
+}
+
+pub fn new(config: &AggregateConfig, inputs: &Inputs) -> Result<Aggregate> {
+    let mut sources = vec![];
+    for (index, input) in config.inputs.iter().enumerate() {
+        let weight = config.weights.get(index).copied().unwrap_or(1.0);
+        sources.push((inputs.open(input)?, weight));
+    }
+    Ok(Aggregate {
+        function: config.function,
+        sources,
+        expires_at: None,
+    })
+}
+
+impl Source for Aggregate {
+    /// Over the inputs with a reading, a missing one does not fail the others.
+    fn read(&mut self) -> Result<f32> {
+        let mut values = vec![];
+        let mut expires_at: Option<Instant> = None;
+        for (source, weight) in self.sources.iter_mut() {
+            if let Ok(value) = source.read() {
+                values.push((value, *weight));
+                expires_at = match (expires_at, source.expires_at()) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+            }
+        }
+        self.expires_at = expires_at;
+        combine(self.function, &values).ok_or_else(|| anyhow!("no input has a reading"))
+    }
+
+    fn expires_at(&self) -> Option<Instant> {
+        self.expires_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::input::{self, mock::Scripted, Push, Remote, RemoteValues};
+
+    const VALUES: [(f32, f32); 3] = [(30.0, 1.0), (50.0, 3.0), (40.0, 0.0)];
+
+    fn aggregate(inputs: &[&str]) -> AggregateConfig {
+        AggregateConfig {
+            function: Function::Max,
+            inputs: inputs
+                .iter()
+                .map(|name| SourceConfig::Sensor {
+                    name: name.to_string(),
+                })
+                .collect(),
+            weights: vec![],
+        }
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(combine(Function::Max, &VALUES), Some(50.0));
+        assert_eq!(combine(Function::Min, &VALUES), Some(30.0));
+        assert_eq!(combine(Function::Avg, &VALUES), Some(40.0));
+        // (30 * 1 + 50 * 3) / 4
+        assert_eq!(combine(Function::Weighted, &VALUES), Some(45.0));
+    }
+
+    #[test]
+    fn a_single_value() {
+        for function in [
+            Function::Max,
+            Function::Min,
+            Function::Avg,
+            Function::Weighted,
+        ] {
+            assert_eq!(combine(function, &[(21.5, 2.0)]), Some(21.5));
+        }
+    }
+
+    #[test]
+    fn no_values() {
+        for function in [
+            Function::Max,
+            Function::Min,
+            Function::Avg,
+            Function::Weighted,
+        ] {
+            assert_eq!(combine(function, &[]), None);
+        }
+        // only inputs without weight have a reading
+        assert_eq!(combine(Function::Weighted, &[(40.0, 0.0)]), None);
+    }
+
+    #[test]
+    fn stale_inputs_are_left_out() {
+        let mut aggregate = Aggregate {
+            function: Function::Weighted,
+            sources: vec![
+                (
+                    Box::new(Scripted::new(vec![Some(30.0), Some(30.0), None])),
+                    1.0,
+                ),
+                (Box::new(Scripted::new(vec![Some(50.0), None, None])), 3.0),
+            ],
+            expires_at: None,
+        };
+        assert_eq!(aggregate.read().unwrap(), 45.0);
+        // the weights spread over the inputs with a reading
+        assert_eq!(aggregate.read().unwrap(), 30.0);
+        assert!(aggregate.read().is_err());
+    }
+
+    #[test]
+    fn validate() {
+        assert!(aggregate(&["a", "b"]).validate().is_ok());
+        assert!(aggregate(&[]).validate().is_err());
+        assert!(aggregate(&[""]).validate().is_err());
+        let time = AggregateConfig {
+            inputs: vec![SourceConfig::Time { period: 0 }],
+            ..aggregate(&[])
+        };
+        assert!(time.validate().is_err());
+
+        let weighted = |weights: Vec<f32>| AggregateConfig {
+            function: Function::Weighted,
+            weights,
+            ..aggregate(&["a", "b"])
+        };
+        assert!(weighted(vec![1.0, 0.0]).validate().is_ok());
+        assert!(weighted(vec![1.0]).validate().is_err());
+        assert!(weighted(vec![1.0, -1.0]).validate().is_err());
+        assert!(weighted(vec![0.0, 0.0]).validate().is_err());
+    }
+
+    #[test]
+    fn no_cycle() {
+        let case = aggregate(&["cpu", "gpu"]);
+        let room = aggregate(&["case", "outside"]);
+        // reached twice, without a loop
+        let all = aggregate(&["case", "room"]);
+        assert_eq!(
+            cycle(&[("case", &case), ("room", &room), ("all", &all)]),
+            None
+        );
+        assert_eq!(cycle(&[]), None);
+    }
+
+    #[test]
+    fn cycles() {
+        let a = aggregate(&["a"]);
+        assert_eq!(cycle(&[("a", &a)]), Some(vec!["a", "a"]));
+
+        let a = aggregate(&["cpu", "b"]);
+        let b = aggregate(&["a"]);
+        assert_eq!(cycle(&[("a", &a), ("b", &b)]), Some(vec!["a", "b", "a"]));
+
+        // entered from outside the loop
+        let top = aggregate(&["a"]);
+        let a = aggregate(&["b"]);
+        let b = aggregate(&["c", "gpu"]);
+        let c = aggregate(&["a"]);
+        assert_eq!(
+            cycle(&[("top", &top), ("a", &a), ("b", &b), ("c", &c)]),
+            Some(vec!["a", "b", "c", "a"])
+        );
+    }
+
+    #[test]
+    fn expires_with_the_first_input() {
+        let values: RemoteValues = Default::default();
+        for (name, ttl) in [("soon", 30), ("later", 60_000)] {
+            let push = Push {
+                name: name.to_string(),
+                value: 20.0,
+                ttl: Some(ttl),
+            };
+            input::push(&values, push).unwrap();
+        }
+        let expires_at = |name: &str| values.lock().unwrap()[name].expires_at();
+        let remote = |name: &str| -> (Box<dyn Source>, f32) {
+            (Box::new(Remote::new(Arc::clone(&values), name)), 1.0)
+        };
+        let mut aggregate = Aggregate {
+            function: Function::Max,
+            sources: vec![
+                remote("later"),
+                remote("soon"),
+                (Box::new(Scripted::new(vec![Some(30.0), Some(30.0)])), 1.0),
+            ],
+            expires_at: None,
+        };
+
+        assert_eq!(aggregate.read().unwrap(), 30.0);
+        assert_eq!(aggregate.expires_at(), expires_at("soon"));
+
+        // left out once expired
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(aggregate.read().unwrap(), 30.0);
+        assert_eq!(aggregate.expires_at(), expires_at("later"));
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Kind {
+    /// the protection source reached its critical reading
+    OverTemperature,
+    /// the protection source kept rising at full duty
+    ThermalRunaway,
+    /// the fan does not turn after all kick-starts
+    FanStalled,
+    /// the current of the output stayed above a trip of its curve
+    Overcurrent,
+}
+
+/// Latched until acknowledged, even once its condition is gone.
+#[derive(Debug, Clone)]
+struct Alarm {
+    kind: Kind,
+    message: String,
+    active: bool,
+    count: u32,
+    raised_at: Instant,
+}
+
+/// See `GET /alarms`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub kind: Kind,
+    /// of the last time it was raised
+    pub message: String,
+    /// the condition still holds
+    pub active: bool,
+    /// times raised since it latched
+    pub count: u32,
+    /// seconds since it latched
+    pub age: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Board {
+    alarms: Vec<Alarm>,
+}
+
+impl Board {
+    /// Latches an alarm of `kind`, or marks the latched one active again.
+    pub fn raise(&mut self, kind: Kind, message: String) {
+        match self.alarms.iter_mut().find(|alarm| alarm.kind == kind) {
+            Some(alarm) => {
+                if !alarm.active {
+                    alarm.active = true;
+                    alarm.count += 1;
+                    error!("alarm {:?} again: {}", kind, message);
+                }
+                alarm.message = message;
+            }
+            None => {
+                error!("alarm {:?}: {}", kind, message);
+                self.alarms.push(Alarm {
+                    kind,
+                    message,
+                    active: true,
+                    count: 1,
+                    raised_at: Instant::now(),
+                });
+            }
+        }
+    }
+
+    /// The condition of `kind` is gone, the alarm stays latched.
+    pub fn clear(&mut self, kind: Kind) {
+        if let Some(alarm) = self.alarms.iter_mut().find(|alarm| alarm.kind == kind) {
+            alarm.active = false;
+        }
+    }
+
+    pub fn latched(&self, kind: Kind) -> bool {
+        self.alarms.iter().any(|alarm| alarm.kind == kind)
+    }
+
+    pub fn any(&self) -> bool {
+        !self.alarms.is_empty()
+    }
+
+    /// Unlatches the alarm of `kind`, or all of them, returns how many.
+    /// One whose condition still holds is raised again by the next check.
+    pub fn acknowledge(&mut self, kind: Option<Kind>) -> usize {
+        let before = self.alarms.len();
+        self.alarms
+            .retain(|alarm| kind.is_some_and(|kind| alarm.kind != kind));
+        let acknowledged = before - self.alarms.len();
+        if acknowledged > 0 {
+            info!("{} alarms acknowledged", acknowledged);
+        }
+        acknowledged
+    }
+
+    pub fn report(&self) -> Vec<Report> {
+        self.alarms
+            .iter()
+            .map(|alarm| Report {
+                kind: alarm.kind,
+                message: alarm.message.clone(),
+                active: alarm.active,
+                count: alarm.count,
+                age: alarm.raised_at.elapsed().as_secs(),
+            })
+            .collect()
+    }
+}
+
+/// Shared by the HTTP handlers and the player.
+pub type Alarms = Arc<Mutex<Board>>;
@@ -0,0 +1,189 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::pins;
+
+/// A quadrature encoder on the shaft of the output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncoderConfig {
+    pub a: i32,
+    pub b: i32,
+    /// pulses once a revolution, the angle is 0 at its rising edge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<i32>,
+    /// edges of both channels a revolution, 4 times the lines of the encoder
+    #[serde(rename = "countsPerRev")]
+    pub counts_per_rev: u32,
+}
+
+impl EncoderConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.counts_per_rev == 0 {
+            return Err(anyhow!("encoder countsPerRev must not be 0"));
+        }
+        pins::check_all(&self.pins())
+    }
+
+    /// Every pin of the encoder by name.
+    pub fn pins(&self) -> Vec<(String, i32)> {
+        let mut pins = vec![
+            ("encoder a".to_string(), self.a),
+            ("encoder b".to_string(), self.b),
+        ];
+        if let Some(index) = self.index {
+            pins.push(("encoder index".to_string(), index));
+        }
+        pins
+    }
+}
+
+/// Counts of a move from the `previous` to the `current` level of both channels, `a << 1 | b`.
+/// A skipped state is ambiguous and counts nothing.
+pub fn quadrature(previous: u8, current: u8) -> i32 {
+    const STEPS: [i32; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+    STEPS[((previous & 3) << 2 | (current & 3)) as usize]
+}
+
+/// Degrees of a count in `0..360`.
+pub fn degrees(count: i32, counts_per_rev: u32) -> f32 {
+    if counts_per_rev == 0 {
+        return 0.0;
+    }
+    let counts_per_rev = counts_per_rev as i64;
+    (count as i64).rem_euclid(counts_per_rev) as f32 * 360.0 / counts_per_rev as f32
+}
+
+/// Plays steps as a function of the shaft angle instead of time,
+/// e.g. a torque curve following the rotor.
+/// Steps are spread evenly over a revolution and interpolated in between, the last one towards the first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Angle {
+    /// degrees added to the angle of the encoder, aligns the curve with the rotor
+    #[serde(default)]
+    pub offset: f32,
+    /// played until the index of the encoder is found, e.g. to turn the rotor slowly
+    #[serde(rename = "homingDuty", default)]
+    pub homing_duty: i32,
+}
+
+/// The duty at `degrees`, wrapped to one revolution.
+pub fn duty_at(steps: &[i32], degrees: f32) -> f32 {
+    let len = steps.len();
+    if len == 0 {
+        return 0.0;
+    }
+
+    let position = degrees.rem_euclid(360.0) / 360.0 * len as f32;
+    // rounding of rem_euclid can give 360 exactly
+    let index = (position.floor() as usize).min(len - 1);
+
+    let from = steps[index] as f32;
+    let to = steps[(index + 1) % len] as f32;
+    from + (to - from) * (position - index as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `a << 1 | b` of a turn counting down, reversed it counts up.
+    const GRAY: [u8; 4] = [0b00, 0b01, 0b11, 0b10];
+
+    fn encoder(a: i32, b: i32, index: Option<i32>) -> EncoderConfig {
+        EncoderConfig {
+            a,
+            b,
+            index,
+            counts_per_rev: 2048,
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert!(encoder(6, 7, Some(10)).validate().is_ok());
+        assert!(encoder(6, 7, None).validate().is_ok());
+        assert!(encoder(6, 6, None).validate().is_err());
+        assert!(encoder(6, 7, Some(7)).validate().is_err());
+        assert!(encoder(6, 64, None).validate().is_err());
+        assert!(encoder(-1, 7, None).validate().is_err());
+        assert!(encoder(6, 7, Some(12)).validate().is_err());
+        // the output of either board
+        assert!(encoder(3, 7, None).validate().is_err());
+        let zero = EncoderConfig {
+            counts_per_rev: 0,
+            ..encoder(6, 7, None)
+        };
+        assert!(zero.validate().is_err());
+    }
+
+    #[test]
+    fn quadrature_counts_each_edge() {
+        let mut count = 0;
+        for i in 0..8 {
+            count += quadrature(GRAY[i % 4], GRAY[(i + 1) % 4]);
+        }
+        assert_eq!(count, -8);
+
+        for i in 0..8 {
+            count += quadrature(GRAY[(i + 1) % 4], GRAY[i % 4]);
+        }
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn quadrature_skipped_states() {
+        // both channels changed at once, the direction is unknown
+        assert_eq!(quadrature(0b00, 0b11), 0);
+        assert_eq!(quadrature(0b11, 0b00), 0);
+        assert_eq!(quadrature(0b01, 0b10), 0);
+        assert_eq!(quadrature(0b10, 0b01), 0);
+        for state in GRAY {
+            assert_eq!(quadrature(state, state), 0);
+        }
+        // only the levels of both channels count
+        assert_eq!(quadrature(0b100, 0b101), quadrature(0b00, 0b01));
+    }
+
+    #[test]
+    fn degrees_wrap() {
+        assert_eq!(degrees(0, 2048), 0.0);
+        assert_eq!(degrees(512, 2048), 90.0);
+        assert_eq!(degrees(2048, 2048), 0.0);
+        assert_eq!(degrees(3 * 2048 + 1024, 2048), 180.0);
+        assert_eq!(degrees(-512, 2048), 270.0);
+        assert_eq!(degrees(-2048, 2048), 0.0);
+        assert_eq!(degrees(i32::MIN, 2048), 0.0);
+        assert_eq!(degrees(512, 0), 0.0);
+    }
+
+    #[test]
+    fn duty_at_interpolates() {
+        let steps = [0, 100, 200, 100];
+        assert_eq!(duty_at(&steps, 0.0), 0.0);
+        assert_eq!(duty_at(&steps, 45.0), 50.0);
+        assert_eq!(duty_at(&steps, 90.0), 100.0);
+        assert_eq!(duty_at(&steps, 180.0), 200.0);
+        // the last step towards the first
+        assert_eq!(duty_at(&steps, 315.0), 50.0);
+    }
+
+    #[test]
+    fn duty_at_wraps() {
+        let steps = [0, 100, 200, 100];
+        assert_eq!(duty_at(&steps, 360.0), 0.0);
+        assert_eq!(duty_at(&steps, 720.0 + 90.0), 100.0);
+        assert_eq!(duty_at(&steps, -90.0), 100.0);
+        // rem_euclid rounds to 360
+        assert!(duty_at(&steps, -1e-6).abs() < 1e-3);
+        let duty = duty_at(&steps, 359.99);
+        assert!((0.0..0.1).contains(&duty), "{}", duty);
+    }
+
+    #[test]
+    fn duty_at_few_steps() {
+        assert_eq!(duty_at(&[], 90.0), 0.0);
+        assert_eq!(duty_at(&[42], 90.0), 42.0);
+        assert_eq!(duty_at(&[0, 100], 90.0), 50.0);
+        assert_eq!(duty_at(&[0, 100], 270.0), 50.0);
+    }
+}
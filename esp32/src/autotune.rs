@@ -0,0 +1,556 @@
+use std::{
+    f32::consts::PI,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::Source,
+    pid::{Gains, Pid},
+};
+
+/// Tuning rule of the proposed gains.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Rule {
+    /// fast, with some overshoot
+    #[default]
+    ZieglerNichols,
+    /// slower and more robust, suits slow thermal loads
+    TyreusLuyben,
+}
+
+/// Request of `POST /pid/autotune`, the PID of the playing config gives source, limits and direction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Settings {
+    /// the setpoint of the PID when tuning starts by default
+    #[serde(default)]
+    pub setpoint: Option<f32>,
+    /// the duty switches between these, `maxPWM` and `minPWM` of the PID by default
+    #[serde(default)]
+    pub high: Option<f32>,
+    #[serde(default)]
+    pub low: Option<f32>,
+    /// how far the reading has to cross the setpoint before the duty switches, against noise
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f32,
+    /// oscillations measured after the first one
+    #[serde(default = "default_cycles")]
+    pub cycles: usize,
+    /// in seconds
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub rule: Rule,
+}
+
+fn default_hysteresis() -> f32 {
+    0.5
+}
+
+fn default_cycles() -> usize {
+    4
+}
+
+fn default_timeout() -> u64 {
+    3600
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<()> {
+        if self.hysteresis < 0.0 {
+            return Err(anyhow!("autotune hysteresis must not be negative"));
+        }
+        if self.cycles == 0 {
+            return Err(anyhow!("autotune cycles must not be 0"));
+        }
+        if self.timeout == 0 {
+            return Err(anyhow!("autotune timeout must not be 0"));
+        }
+        match (self.high, self.low) {
+            (Some(high), Some(low)) if high <= low => {
+                Err(anyhow!("autotune high must be greater than low"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Running,
+    Done,
+    Failed,
+}
+
+/// Progress of a tuning, see `GET /pid/autotune`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub phase: Phase,
+    pub setpoint: f32,
+    /// oscillations measured so far
+    pub cycles: usize,
+    /// seconds since tuning started
+    pub elapsed: f32,
+    /// seconds of an oscillation
+    pub period: Option<f32>,
+    /// half of the peak to peak reading
+    pub amplitude: Option<f32>,
+    #[serde(rename = "ultimateGain")]
+    pub ultimate_gain: Option<f32>,
+    /// proposed, apply them with `PUT /pid`
+    pub gains: Option<Gains>,
+    pub error: Option<String>,
+}
+
+impl Status {
+    /// A tuning that could not start, e.g. the playing config has no PID anymore.
+    pub fn failed(setpoint: Option<f32>, error: &str) -> Self {
+        Status {
+            phase: Phase::Failed,
+            setpoint: setpoint.unwrap_or(f32::NAN),
+            cycles: 0,
+            elapsed: 0.0,
+            period: None,
+            amplitude: None,
+            ultimate_gain: None,
+            gains: None,
+            error: Some(error.to_string()),
+        }
+    }
+}
+
+/// The period and amplitude of the oscillation of a relay in the loop,
+/// with the duty switched fully between `high` and `low` around a setpoint.
+#[derive(Debug, Clone)]
+pub struct Relay {
+    settings: Settings,
+    setpoint: f32,
+    high: f32,
+    low: f32,
+    reverse: bool,
+    driving: bool,
+    /// seconds of each switch to `high`
+    switched_at: Vec<f32>,
+    peak: Option<(f32, f32)>,
+    periods: Vec<f32>,
+    amplitudes: Vec<f32>,
+    elapsed: f32,
+    outcome: Option<Result<Gains, String>>,
+}
+
+impl Relay {
+    pub fn new(settings: &Settings, pid: &Pid, setpoint: f32) -> Self {
+        Relay {
+            settings: settings.clone(),
+            setpoint,
+            high: settings.high.unwrap_or(pid.max_pwm),
+            low: settings.low.unwrap_or(pid.min_pwm),
+            reverse: pid.reverse,
+            driving: false,
+            switched_at: vec![],
+            peak: None,
+            periods: vec![],
+            amplitudes: vec![],
+            elapsed: 0.0,
+            outcome: None,
+        }
+    }
+
+    /// The duty for a reading at `elapsed` seconds since start.
+    pub fn update(&mut self, measurement: f32, elapsed: f32) -> f32 {
+        if self.outcome.is_some() {
+            return self.low;
+        }
+        self.elapsed = elapsed;
+
+        self.peak = Some(match self.peak {
+            Some((min, max)) => (min.min(measurement), max.max(measurement)),
+            None => (measurement, measurement),
+        });
+
+        // positive while the load needs to be driven
+        let error = if self.reverse {
+            measurement - self.setpoint
+        } else {
+            self.setpoint - measurement
+        };
+
+        if !self.driving && error > self.settings.hysteresis {
+            self.driving = true;
+            self.switch_on(measurement, elapsed);
+        } else if self.driving && error < -self.settings.hysteresis {
+            self.driving = false;
+        }
+
+        if self.outcome.is_none() && elapsed > self.settings.timeout as f32 {
+            self.outcome = Some(Err("no steady oscillation before the timeout".to_string()));
+        }
+        if self.outcome.is_some() {
+            return self.low;
+        }
+
+        if self.driving {
+            self.high
+        } else {
+            self.low
+        }
+    }
+
+    /// A switch on closes an oscillation, the first one settles and is not measured.
+    fn switch_on(&mut self, measurement: f32, elapsed: f32) {
+        match (self.switched_at.last(), self.peak) {
+            (Some(last), Some((min, max))) if self.switched_at.len() >= 2 => {
+                self.periods.push(elapsed - last);
+                self.amplitudes.push((max - min) / 2.0);
+            }
+            _ => {}
+        }
+        self.switched_at.push(elapsed);
+        self.peak = Some((measurement, measurement));
+
+        if self.periods.len() >= self.settings.cycles {
+            self.outcome = Some(self.gains());
+        }
+    }
+
+    fn gains(&self) -> Result<Gains, String> {
+        let (period, amplitude) = match (self.period(), self.amplitude()) {
+            (Some(period), Some(amplitude)) => (period, amplitude),
+            _ => return Err("no oscillation measured".to_string()),
+        };
+        let hysteresis = self.settings.hysteresis;
+        if amplitude <= hysteresis {
+            return Err("oscillation within the hysteresis, lower it".to_string());
+        }
+
+        let ku = self.ultimate_gain(amplitude);
+        Ok(match self.settings.rule {
+            Rule::ZieglerNichols => {
+                let kp = 0.6 * ku;
+                Gains {
+                    kp,
+                    ki: kp / (period / 2.0),
+                    kd: kp * period / 8.0,
+                }
+            }
+            Rule::TyreusLuyben => {
+                let kp = ku / 2.2;
+                Gains {
+                    kp,
+                    ki: kp / (2.2 * period),
+                    kd: kp * period / 6.3,
+                }
+            }
+        })
+    }
+
+    /// The describing function of a relay with hysteresis.
+    fn ultimate_gain(&self, amplitude: f32) -> f32 {
+        let d = (self.high - self.low) / 2.0;
+        let hysteresis = self.settings.hysteresis;
+        4.0 * d / (PI * (amplitude * amplitude - hysteresis * hysteresis).sqrt())
+    }
+
+    fn period(&self) -> Option<f32> {
+        average(&self.periods)
+    }
+
+    fn amplitude(&self) -> Option<f32> {
+        average(&self.amplitudes)
+    }
+
+    pub fn status(&self) -> Status {
+        let amplitude = self.amplitude();
+        let (phase, gains, error) = match &self.outcome {
+            None => (Phase::Running, None, None),
+            Some(Ok(gains)) => (Phase::Done, Some(*gains), None),
+            Some(Err(e)) => (Phase::Failed, None, Some(e.clone())),
+        };
+        Status {
+            phase,
+            setpoint: self.setpoint,
+            cycles: self.periods.len(),
+            elapsed: self.elapsed,
+            period: self.period(),
+            amplitude,
+            ultimate_gain: amplitude
+                .filter(|a| *a > self.settings.hysteresis)
+                .map(|a| self.ultimate_gain(a)),
+            gains,
+            error,
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.outcome.is_some()
+    }
+
+    /// Ends a running tuning, e.g. when its reading is gone.
+    pub fn fail(&mut self, error: String) {
+        if self.outcome.is_none() {
+            self.outcome = Some(Err(error));
+        }
+    }
+}
+
+fn average(values: &[f32]) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    Some(values.iter().sum::<f32>() / values.len() as f32)
+}
+
+/// Runs a relay every `sample_interval` of the PID with a reading of its source.
+pub struct Tuner {
+    relay: Relay,
+    started_at: Instant,
+    sampled_at: Option<Instant>,
+    duty: f32,
+}
+
+impl Tuner {
+    pub fn new(settings: &Settings, pid: &Pid, setpoint: f32) -> Self {
+        let relay = Relay::new(settings, pid, setpoint);
+        Tuner {
+            duty: relay.low,
+            relay,
+            started_at: Instant::now(),
+            sampled_at: None,
+        }
+    }
+
+    pub fn update(&mut self, pid: &Pid, source: &mut dyn Source) -> f32 {
+        match self.sampled_at {
+            Some(at) if (at.elapsed().as_millis() as u64) < pid.sample_interval => {
+                return self.duty;
+            }
+            _ => {}
+        }
+
+        self.sampled_at = Some(Instant::now());
+        self.duty = match source.read() {
+            Ok(measurement) => self
+                .relay
+                .update(measurement, self.started_at.elapsed().as_secs_f32()),
+            Err(e) => {
+                self.relay.fail(format!("reading error: {}", e));
+                pid.fail_safe()
+            }
+        };
+        self.duty
+    }
+
+    pub fn status(&self) -> Status {
+        self.relay.status()
+    }
+
+    pub fn finished(&self) -> bool {
+        self.relay.finished()
+    }
+
+    pub fn fail(&mut self, error: &str) {
+        self.relay.fail(error.to_string());
+    }
+}
+
+/// Shared by the HTTP handlers and the player.
+#[derive(Debug, Default)]
+pub struct Session {
+    /// taken by the player to start a tuning
+    pub request: Option<Settings>,
+    /// taken by the player to end a tuning
+    pub cancel: bool,
+    /// of the running or the last tuning
+    pub status: Option<Status>,
+}
+
+pub type Autotune = Arc<Mutex<Session>>;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::{compose::Node, input::mock::Scripted, input::SourceConfig, pid::State};
+
+    const DT: f32 = 0.1;
+    const AMBIENT: f32 = 25.0;
+
+    fn pid() -> Pid {
+        Pid {
+            source: SourceConfig::default(),
+            setpoint: Node::Constant { value: 45.0 },
+            gains: Gains {
+                kp: 1.0,
+                ki: 0.0,
+                kd: 0.0,
+            },
+            derivative_filter: 0.0,
+            min_pwm: 0.0,
+            max_pwm: 255.0,
+            reverse: false,
+            sample_interval: 0,
+        }
+    }
+
+    fn settings(rule: Rule) -> Settings {
+        serde_json::from_value(serde_json::json!({ "rule": rule })).unwrap()
+    }
+
+    /// A heater with a time constant of 20 s, 0.2 degree above the ambient for each duty,
+    /// read 2 s late, like a sensor on the far side of a heat sink.
+    struct Thermal {
+        temperature: f32,
+        delayed: VecDeque<f32>,
+    }
+
+    impl Thermal {
+        fn new() -> Self {
+            Thermal {
+                temperature: AMBIENT,
+                delayed: VecDeque::from(vec![0.0; 20]),
+            }
+        }
+
+        fn step(&mut self, duty: f32) -> f32 {
+            self.delayed.push_back(duty);
+            let duty = self.delayed.pop_front().unwrap();
+            self.temperature += ((AMBIENT - self.temperature) / 20.0 + 0.01 * duty) * DT;
+            self.temperature
+        }
+    }
+
+    fn tune(rule: Rule) -> Relay {
+        let mut relay = Relay::new(&settings(rule), &pid(), 45.0);
+        let mut thermal = Thermal::new();
+        let mut measurement = thermal.temperature;
+        let mut elapsed = 0.0;
+        while !relay.finished() {
+            let duty = relay.update(measurement, elapsed);
+            measurement = thermal.step(duty);
+            elapsed += DT;
+        }
+        relay
+    }
+
+    #[test]
+    fn measures_the_oscillation() {
+        let status = tune(Rule::ZieglerNichols).status();
+        assert_eq!(status.phase, Phase::Done);
+        assert_eq!(status.cycles, 4);
+        // about 4 times the dead time, a little longer for the hysteresis
+        let period = status.period.unwrap();
+        assert!((9.0..10.5).contains(&period), "{}", period);
+        let amplitude = status.amplitude.unwrap();
+        assert!((2.5..3.5).contains(&amplitude), "{}", amplitude);
+
+        let ku = status.ultimate_gain.unwrap();
+        let expected = 4.0 * 127.5 / (PI * (amplitude * amplitude - 0.25).sqrt());
+        assert!((ku - expected).abs() < 1e-3);
+
+        let gains = status.gains.unwrap();
+        assert!((gains.kp - 0.6 * ku).abs() < 1e-3);
+        assert!((gains.ki - gains.kp * 2.0 / period).abs() < 1e-3);
+        assert!((gains.kd - gains.kp * period / 8.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn tyreus_luyben_is_gentler() {
+        let zn = tune(Rule::ZieglerNichols).status().gains.unwrap();
+        let tl = tune(Rule::TyreusLuyben).status().gains.unwrap();
+        assert!(tl.kp < zn.kp);
+        assert!(tl.ki < zn.ki);
+    }
+
+    #[test]
+    fn proposed_gains_hold_the_setpoint() {
+        for rule in [Rule::ZieglerNichols, Rule::TyreusLuyben] {
+            let pid = Pid {
+                gains: tune(rule).status().gains.unwrap(),
+                ..pid()
+            };
+            let mut state = State::default();
+            let mut thermal = Thermal::new();
+            let mut measurement = thermal.temperature;
+            let trace: Vec<f32> = (0..6000)
+                .map(|_| {
+                    let duty = state.step(&pid, 45.0, measurement, DT);
+                    measurement = thermal.step(duty);
+                    measurement
+                })
+                .collect();
+
+            let settled = &trace[trace.len() - 1000..];
+            assert!(
+                settled.iter().all(|t| (t - 45.0).abs() < 0.05),
+                "{:?}",
+                rule
+            );
+            assert!(trace.iter().all(|t| *t < 48.0), "{:?}", rule);
+        }
+    }
+
+    #[test]
+    fn times_out_without_oscillation() {
+        let settings = Settings {
+            timeout: 60,
+            ..settings(Rule::ZieglerNichols)
+        };
+        let mut relay = Relay::new(&settings, &pid(), 45.0);
+        let mut elapsed = 0.0;
+        // the load never reaches the setpoint
+        while elapsed <= 60.0 {
+            assert_eq!(relay.update(30.0, elapsed), 255.0);
+            elapsed += 1.0;
+        }
+        assert!(!relay.finished());
+        assert_eq!(relay.update(30.0, elapsed), 0.0);
+        assert!(relay.finished());
+        let status = relay.status();
+        assert_eq!(status.phase, Phase::Failed);
+        assert!(status.gains.is_none());
+        assert_eq!(relay.update(30.0, elapsed), 0.0);
+    }
+
+    #[test]
+    fn reverse_drives_above_the_setpoint() {
+        let pid = Pid {
+            reverse: true,
+            ..pid()
+        };
+        let mut relay = Relay::new(&settings(Rule::ZieglerNichols), &pid, 45.0);
+        assert_eq!(relay.update(50.0, 0.0), 255.0);
+        // within the hysteresis
+        assert_eq!(relay.update(44.8, 1.0), 255.0);
+        assert_eq!(relay.update(44.0, 2.0), 0.0);
+    }
+
+    #[test]
+    fn tuner_fails_safe_without_a_reading() {
+        let pid = Pid {
+            reverse: true,
+            ..pid()
+        };
+        let mut tuner = Tuner::new(&settings(Rule::ZieglerNichols), &pid, 45.0);
+        let mut source = Scripted::new(vec![Some(50.0), None]);
+        assert_eq!(tuner.update(&pid, &mut source), 255.0);
+        assert_eq!(tuner.update(&pid, &mut source), pid.fail_safe());
+        assert!(tuner.finished());
+        assert_eq!(tuner.status().phase, Phase::Failed);
+    }
+
+    #[test]
+    fn failed_without_a_pid() {
+        let status = Status::failed(None, "no pid in the playing config");
+        assert_eq!(status.phase, Phase::Failed);
+        assert_eq!(
+            serde_json::to_value(&status).unwrap()["setpoint"],
+            serde_json::Value::Null
+        );
+    }
+}
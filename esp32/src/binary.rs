@@ -0,0 +1,335 @@
+use std::io::{self, Read};
+
+use crate::validation::Invalid;
+
+/**
+ * Diagram, big endian
+ * magic "CPWM", version_u8, mode_u8, interval_u64, count_u32, sample * count
+ * a sample is a u8, i16 or i32 by mode 0, 1 or 2
+ */
+const MAGIC: &[u8; 4] = b"CPWM";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 18;
+
+/// How each sample is packed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// 0 to 255, the duties of the default resolution
+    U8 = 0,
+    /// negative duties play in reverse
+    I16 = 1,
+    I32 = 2,
+}
+
+impl Mode {
+    fn from_u8(mode: u8) -> Option<Mode> {
+        match mode {
+            0 => Some(Mode::U8),
+            1 => Some(Mode::I16),
+            2 => Some(Mode::I32),
+            _ => None,
+        }
+    }
+
+    /// The narrowest mode that holds every step.
+    pub fn fit(steps: &[i32]) -> Mode {
+        if steps.iter().all(|step| (0..=u8::MAX as i32).contains(step)) {
+            Mode::U8
+        } else if steps
+            .iter()
+            .all(|step| (i16::MIN as i32..=i16::MAX as i32).contains(step))
+        {
+            Mode::I16
+        } else {
+            Mode::I32
+        }
+    }
+
+    pub fn sample_size(self) -> usize {
+        match self {
+            Mode::U8 => 1,
+            Mode::I16 => 2,
+            Mode::I32 => 4,
+        }
+    }
+}
+
+/// Packs the steps in the narrowest mode, see `Mode::fit`.
+pub fn encode(interval: u64, steps: &[i32]) -> Vec<u8> {
+    let mode = Mode::fit(steps);
+
+    let mut buffer = Vec::with_capacity(HEADER_SIZE + steps.len() * mode.sample_size());
+    buffer.extend_from_slice(MAGIC);
+    buffer.push(VERSION);
+    buffer.push(mode as u8);
+    buffer.extend_from_slice(&interval.to_be_bytes());
+    buffer.extend_from_slice(&(steps.len() as u32).to_be_bytes());
+
+    for step in steps {
+        match mode {
+            Mode::U8 => buffer.push(*step as u8),
+            Mode::I16 => buffer.extend_from_slice(&(*step as i16).to_be_bytes()),
+            Mode::I32 => buffer.extend_from_slice(&step.to_be_bytes()),
+        }
+    }
+
+    buffer
+}
+
+/// `interval` and steps of an upload or of the config file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoded {
+    pub interval: u64,
+    pub steps: Vec<i32>,
+}
+
+fn read_exact(reader: &mut impl Read, buffer: &mut [u8], field: &str) -> Result<(), Invalid> {
+    reader.read_exact(buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => Invalid::malformed_at(field, "ends early"),
+        _ => Invalid::malformed_at(field, e),
+    })
+}
+
+/// Reads the samples as they arrive, the count is checked against `limit`
+/// before anything is allocated.
+pub fn read(mut reader: impl Read, limit: usize) -> Result<Decoded, Invalid> {
+    let mut header = [0u8; HEADER_SIZE];
+    read_exact(&mut reader, &mut header, "header")?;
+
+    if &header[0..4] != MAGIC {
+        return Err(Invalid::malformed_at("magic", "not a curved pwm config"));
+    }
+    if header[4] != VERSION {
+        return Err(Invalid::malformed_at(
+            "version",
+            format!("version {} is not {}", header[4], VERSION),
+        ));
+    }
+    let mode = match Mode::from_u8(header[5]) {
+        Some(mode) => mode,
+        None => {
+            return Err(Invalid::malformed_at(
+                "mode",
+                format!("unknown mode {}", header[5]),
+            ))
+        }
+    };
+    let interval = u64::from_be_bytes(header[6..14].try_into().unwrap());
+    let count = u32::from_be_bytes(header[14..18].try_into().unwrap()) as usize;
+    if count > limit {
+        return Err(Invalid::too_large(
+            "count",
+            format!("more than {} steps", limit),
+        ));
+    }
+
+    let mut steps = Vec::with_capacity(count);
+    let mut sample = [0u8; 4];
+    let sample = &mut sample[..mode.sample_size()];
+    for _ in 0..count {
+        read_exact(&mut reader, sample, "samples")?;
+        steps.push(match mode {
+            Mode::U8 => sample[0] as i32,
+            Mode::I16 => i16::from_be_bytes([sample[0], sample[1]]) as i32,
+            Mode::I32 => i32::from_be_bytes([sample[0], sample[1], sample[2], sample[3]]),
+        });
+    }
+
+    let mut rest = [0u8; 1];
+    match reader.read(&mut rest) {
+        Ok(0) => {}
+        Ok(_) => return Err(Invalid::malformed_at("count", "more samples than count")),
+        Err(e) => return Err(Invalid::malformed_at("samples", e)),
+    }
+
+    Ok(Decoded { interval, steps })
+}
+
+/// The config file before the header, `interval_u64, pwm_i32 * n`.
+/// Its interval would have to be over 4.8e18 ms to start with the magic.
+pub fn read_legacy(bytes: &[u8]) -> Option<Decoded> {
+    if bytes.len() < 8 || (bytes.len() - 8) % 4 != 0 {
+        return None;
+    }
+
+    Some(Decoded {
+        interval: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+        steps: bytes[8..]
+            .chunks_exact(4)
+            .map(|step| i32::from_be_bytes(step.try_into().unwrap()))
+            .collect(),
+    })
+}
+
+/// Tells the config file apart from the one before the header.
+pub fn is_encoded(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::Reason;
+
+    fn read_all(bytes: &[u8]) -> Result<Decoded, Invalid> {
+        read(bytes, 2000)
+    }
+
+    fn rejected(bytes: &[u8], limit: usize) -> (Reason, String) {
+        let invalid = read(bytes, limit).unwrap_err();
+        (invalid.error, invalid.field)
+    }
+
+    #[test]
+    fn round_trip_u8() {
+        let steps = [0, 128, 255];
+        let bytes = encode(100, &steps);
+        assert_eq!(bytes[5], Mode::U8 as u8);
+        assert_eq!(bytes.len(), HEADER_SIZE + 3);
+        assert_eq!(
+            read_all(&bytes).unwrap(),
+            Decoded {
+                interval: 100,
+                steps: steps.to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn round_trip_i16() {
+        let steps = [-255, 0, 256, i16::MIN as i32, i16::MAX as i32];
+        let bytes = encode(30, &steps);
+        assert_eq!(bytes[5], Mode::I16 as u8);
+        assert_eq!(bytes.len(), HEADER_SIZE + 5 * 2);
+        assert_eq!(read_all(&bytes).unwrap().steps, steps);
+    }
+
+    #[test]
+    fn round_trip_i32() {
+        let steps = [i32::MIN, -1, 0, 65536, i32::MAX];
+        let bytes = encode(u64::MAX, &steps);
+        assert_eq!(bytes[5], Mode::I32 as u8);
+        assert_eq!(bytes.len(), HEADER_SIZE + 5 * 4);
+        let decoded = read_all(&bytes).unwrap();
+        assert_eq!(decoded.interval, u64::MAX);
+        assert_eq!(decoded.steps, steps);
+    }
+
+    #[test]
+    fn round_trip_empty() {
+        let bytes = encode(100, &[]);
+        assert_eq!(bytes.len(), HEADER_SIZE);
+        assert!(read_all(&bytes).unwrap().steps.is_empty());
+    }
+
+    #[test]
+    fn layout() {
+        assert_eq!(
+            encode(256, &[1, 2]),
+            [b'C', b'P', b'W', b'M', 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 2, 1, 2]
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = encode(100, &[1000, 2000, 3000]);
+        assert_eq!(
+            rejected(&bytes[..bytes.len() - 1], 2000),
+            (Reason::Malformed, "samples".to_string())
+        );
+        assert_eq!(
+            rejected(&bytes[..HEADER_SIZE - 1], 2000),
+            (Reason::Malformed, "header".to_string())
+        );
+        assert_eq!(
+            rejected(&[], 2000),
+            (Reason::Malformed, "header".to_string())
+        );
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut bytes = encode(100, &[1, 2, 3]);
+        bytes.push(4);
+        assert_eq!(
+            rejected(&bytes, 2000),
+            (Reason::Malformed, "count".to_string())
+        );
+    }
+
+    #[test]
+    fn count_above_limit() {
+        let bytes = encode(100, &[1, 2, 3]);
+        assert!(read(&bytes[..], 3).is_ok());
+        assert_eq!(rejected(&bytes, 2), (Reason::TooLarge, "count".to_string()));
+
+        // checked before the samples, which are never sent
+        let mut header = encode(100, &[]);
+        header[14..18].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(
+            rejected(&header, 2000),
+            (Reason::TooLarge, "count".to_string())
+        );
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut bytes = encode(100, &[1]);
+        bytes[0] = b'X';
+        assert_eq!(
+            rejected(&bytes, 2000),
+            (Reason::Malformed, "magic".to_string())
+        );
+
+        let mut bytes = encode(100, &[1]);
+        bytes[4] = VERSION + 1;
+        assert_eq!(
+            rejected(&bytes, 2000),
+            (Reason::Malformed, "version".to_string())
+        );
+
+        let mut bytes = encode(100, &[1]);
+        bytes[5] = 3;
+        assert_eq!(
+            rejected(&bytes, 2000),
+            (Reason::Malformed, "mode".to_string())
+        );
+    }
+
+    #[test]
+    fn legacy() {
+        let mut bytes = 100u64.to_be_bytes().to_vec();
+        for step in [0i32, -255, 70000] {
+            bytes.extend_from_slice(&step.to_be_bytes());
+        }
+        assert!(!is_encoded(&bytes));
+        assert_eq!(
+            read_legacy(&bytes),
+            Some(Decoded {
+                interval: 100,
+                steps: vec![0, -255, 70000]
+            })
+        );
+
+        assert_eq!(
+            read_legacy(&100u64.to_be_bytes()),
+            Some(Decoded {
+                interval: 100,
+                steps: vec![]
+            })
+        );
+        assert_eq!(read_legacy(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(read_legacy(&bytes[..7]), None);
+    }
+
+    #[test]
+    fn tells_the_formats_apart() {
+        let encoded = encode(100, &[1, 2, 3]);
+        assert!(is_encoded(&encoded));
+        assert!(!is_encoded(&[]));
+        assert!(!is_encoded(b"CPW"));
+        // a legacy interval would have to be above 4.8e18 ms to start with the magic
+        let magic = u64::from_be_bytes(*b"CPWM\0\0\0\0");
+        assert!(magic > 4_800_000_000_000_000_000);
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/**
+ * Diagram
+ * json of the Calibration of the fan on the output
+ */
+static CALIBRATION_FILE_NAME: &str = "/spiffs/calibration.json";
+
+/// Request of `POST /calibration`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Settings {
+    /// duty between two points of the sweep
+    #[serde(default = "default_step")]
+    pub step: u32,
+    /// milliseconds at each point before its revolutions are taken
+    #[serde(default = "default_settle")]
+    pub settle: u64,
+    /// top of the sweep
+    #[serde(rename = "maxPWM", default = "default_max_pwm")]
+    pub max_pwm: u32,
+}
+
+fn default_step() -> u32 {
+    5
+}
+
+fn default_settle() -> u64 {
+    3000
+}
+
+fn default_max_pwm() -> u32 {
+    255
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<()> {
+        if self.step == 0 {
+            return Err(anyhow!("calibration step must not be 0"));
+        }
+        if self.max_pwm < self.step {
+            return Err(anyhow!("calibration maxPWM must not be below step"));
+        }
+        // the tachometer takes a second for a reading
+        if self.settle < 1000 {
+            return Err(anyhow!("calibration settle must be at least 1000"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub duty: u32,
+    pub rpm: f32,
+}
+
+/// The usable range of the fan on the output, measured by a sweep.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Calibration {
+    /// lowest duty that starts a standing fan
+    pub start: u32,
+    /// lowest duty that keeps a turning fan turning
+    pub stop: u32,
+    /// revolutions of the sweep up from 0
+    pub up: Vec<Point>,
+    /// revolutions of the sweep down to standstill
+    pub down: Vec<Point>,
+}
+
+impl Calibration {
+    /// Spreads a nonzero duty over the usable range from `stop` to `max_duty`,
+    /// so the low end of a curve turns the fan slowly instead of not at all.
+    pub fn map(&self, duty: u32, max_duty: u32) -> u32 {
+        if duty == 0 || max_duty <= 1 || self.stop >= max_duty {
+            return duty;
+        }
+        let duty = duty.min(max_duty);
+        let range = (max_duty - self.stop) as f32;
+        self.stop + ((duty - 1) as f32 * range / (max_duty - 1) as f32).round() as u32
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// Plays duties from 0 up to `maxPWM` and back down until the fan stops,
+/// taking the revolutions at each after it settled.
+#[derive(Debug, Clone)]
+pub struct Sweep {
+    settings: Settings,
+    direction: Direction,
+    duty: u32,
+    since: Option<Instant>,
+    up: Vec<Point>,
+    down: Vec<Point>,
+    outcome: Option<Result<Calibration, String>>,
+}
+
+impl Sweep {
+    pub fn new(settings: &Settings) -> Self {
+        Sweep {
+            settings: settings.clone(),
+            direction: Direction::Up,
+            duty: 0,
+            since: None,
+            up: vec![],
+            down: vec![],
+            outcome: None,
+        }
+    }
+
+    /// The duty to play, 0 once finished.
+    pub fn update(&mut self, rpm: Option<f32>, now: Instant) -> u32 {
+        if self.outcome.is_some() {
+            return 0;
+        }
+
+        let since = *self.since.get_or_insert(now);
+        if now - since < Duration::from_millis(self.settings.settle) {
+            return self.duty;
+        }
+
+        let rpm = match rpm {
+            Some(rpm) => rpm,
+            None => {
+                self.fail("no revolutions measured");
+                return 0;
+            }
+        };
+        let point = Point {
+            duty: self.duty,
+            rpm,
+        };
+        self.since = Some(now);
+
+        match self.direction {
+            Direction::Up => {
+                self.up.push(point);
+                if self.duty + self.settings.step <= self.settings.max_pwm {
+                    self.duty += self.settings.step;
+                } else {
+                    self.direction = Direction::Down;
+                    self.duty = self.duty.saturating_sub(self.settings.step);
+                }
+            }
+            Direction::Down => {
+                self.down.push(point);
+                if rpm <= 0.0 || self.duty == 0 {
+                    self.outcome = Some(self.measure());
+                    return 0;
+                }
+                self.duty = self.duty.saturating_sub(self.settings.step);
+            }
+        }
+        self.duty
+    }
+
+    fn measure(&self) -> Result<Calibration, String> {
+        let start = self
+            .up
+            .iter()
+            .find(|point| point.rpm > 0.0)
+            .ok_or_else(|| "the fan never turned".to_string())?
+            .duty;
+        let stop = self
+            .down
+            .iter()
+            .filter(|point| point.rpm > 0.0)
+            .map(|point| point.duty)
+            .min()
+            .unwrap_or(start);
+
+        Ok(Calibration {
+            start,
+            stop: stop.min(start),
+            up: self.up.clone(),
+            down: self.down.clone(),
+        })
+    }
+
+    pub fn fail(&mut self, error: &str) {
+        if self.outcome.is_none() {
+            self.outcome = Some(Err(error.to_string()));
+        }
+    }
+
+    pub fn finished(&self) -> bool {
+        self.outcome.is_some()
+    }
+
+    /// The measured calibration once finished.
+    pub fn calibration(&self) -> Option<&Calibration> {
+        match &self.outcome {
+            Some(Ok(calibration)) => Some(calibration),
+            _ => None,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let (phase, error) = match &self.outcome {
+            None => (Phase::Running, None),
+            Some(Ok(_)) => (Phase::Done, None),
+            Some(Err(e)) => (Phase::Failed, Some(e.clone())),
+        };
+        Status {
+            phase,
+            direction: self.direction,
+            duty: self.duty,
+            points: self.up.len() + self.down.len(),
+            error,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Running,
+    Done,
+    Failed,
+}
+
+/// Progress of a sweep, see `GET /calibration`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub phase: Phase,
+    pub direction: Direction,
+    pub duty: u32,
+    /// measured so far
+    pub points: usize,
+    pub error: Option<String>,
+}
+
+/// Shared by the HTTP handlers and the player.
+#[derive(Debug, Default)]
+pub struct Session {
+    /// taken by the player to start a sweep
+    pub request: Option<Settings>,
+    /// taken by the player to end a sweep
+    pub cancel: bool,
+    /// of the running or the last sweep
+    pub status: Option<Status>,
+    /// in use by the player
+    pub calibration: Option<Calibration>,
+}
+
+pub type Calibrate = Arc<Mutex<Session>>;
+
+pub fn get() -> Result<Option<Calibration>> {
+    if !fs::exists(CALIBRATION_FILE_NAME)? {
+        return Ok(None);
+    }
+
+    match serde_json::from_slice(&fs::read(CALIBRATION_FILE_NAME)?) {
+        Ok(calibration) => Ok(Some(calibration)),
+        Err(e) => {
+            warn!("invalid calibration file, removing it: {:?}", e);
+            fs::remove_file(CALIBRATION_FILE_NAME)?;
+            Ok(None)
+        }
+    }
+}
+
+pub fn save(calibration: &Calibration) -> Result<()> {
+    fs::write(CALIBRATION_FILE_NAME, serde_json::to_vec(calibration)?)?;
+
+    Ok(())
+}
+
+pub fn delete() -> Result<()> {
+    if fs::exists(CALIBRATION_FILE_NAME)? {
+        fs::remove_file(CALIBRATION_FILE_NAME)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: Duration = Duration::from_millis(100);
+
+    fn settings() -> Settings {
+        Settings {
+            step: 5,
+            settle: 1000,
+            max_pwm: 100,
+        }
+    }
+
+    /// Feeds the revolutions at the played duty every 100 ms until the sweep finished,
+    /// the time it took.
+    fn run(sweep: &mut Sweep, mut rpm: impl FnMut(u32) -> Option<f32>) -> Duration {
+        let start = Instant::now();
+        let mut at = Duration::ZERO;
+        let mut duty = 0;
+        while !sweep.finished() {
+            assert!(at < Duration::from_secs(600), "the sweep never finished");
+            duty = sweep.update(rpm(duty), start + at);
+            at += SAMPLE;
+        }
+        assert_eq!(duty, 0);
+        at
+    }
+
+    /// Starts at 40 and keeps turning down to 25.
+    fn fan() -> impl FnMut(u32) -> Option<f32> {
+        let mut turning = false;
+        move |duty| {
+            turning = duty >= if turning { 25 } else { 40 };
+            Some(if turning { duty as f32 * 10.0 } else { 0.0 })
+        }
+    }
+
+    fn calibration(start: u32, stop: u32) -> Calibration {
+        Calibration {
+            start,
+            stop,
+            up: vec![],
+            down: vec![],
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert!(settings().validate().is_ok());
+        let zero_step = Settings {
+            step: 0,
+            ..settings()
+        };
+        assert!(zero_step.validate().is_err());
+        let short = Settings {
+            settle: 999,
+            ..settings()
+        };
+        assert!(short.validate().is_err());
+    }
+
+    #[test]
+    fn finds_start_and_stop() {
+        let mut sweep = Sweep::new(&settings());
+        run(&mut sweep, fan());
+
+        let calibration = sweep.calibration().unwrap();
+        assert_eq!(calibration.start, 40);
+        assert_eq!(calibration.stop, 25);
+        // 0 to 100 up, 95 to 20 down
+        assert_eq!(calibration.up.len(), 21);
+        assert_eq!(calibration.up.last().unwrap().duty, 100);
+        assert_eq!(calibration.down.len(), 16);
+        assert_eq!(
+            calibration.down.last().unwrap(),
+            &Point { duty: 20, rpm: 0.0 }
+        );
+        assert_eq!(sweep.status().phase, Phase::Done);
+    }
+
+    #[test]
+    fn plays_each_duty_for_the_settle_time() {
+        let mut sweep = Sweep::new(&settings());
+        let took = run(&mut sweep, fan());
+        // 37 points of 1 s each
+        assert!(took >= Duration::from_secs(37) && took < Duration::from_secs(38));
+    }
+
+    #[test]
+    fn no_revolutions_measured_in_time() {
+        let mut sweep = Sweep::new(&settings());
+        let took = run(&mut sweep, |_| None);
+        assert_eq!(took, Duration::from_millis(1100));
+        assert!(sweep.calibration().is_none());
+        let status = sweep.status();
+        assert_eq!(status.phase, Phase::Failed);
+        assert_eq!(status.error.as_deref(), Some("no revolutions measured"));
+    }
+
+    #[test]
+    fn the_fan_never_turned() {
+        let mut sweep = Sweep::new(&settings());
+        run(&mut sweep, |_| Some(0.0));
+        assert!(sweep.calibration().is_none());
+        assert_eq!(
+            sweep.status().error.as_deref(),
+            Some("the fan never turned")
+        );
+    }
+
+    #[test]
+    fn a_failure_is_kept() {
+        let mut sweep = Sweep::new(&settings());
+        sweep.fail("cancelled");
+        assert!(sweep.finished());
+        assert_eq!(sweep.update(Some(1000.0), Instant::now()), 0);
+        sweep.fail("overcurrent");
+        assert_eq!(sweep.status().error.as_deref(), Some("cancelled"));
+    }
+
+    #[test]
+    fn map_ends() {
+        let calibration = calibration(40, 25);
+        assert_eq!(calibration.map(0, 255), 0);
+        assert_eq!(calibration.map(1, 255), 25);
+        assert_eq!(calibration.map(255, 255), 255);
+        // above the top
+        assert_eq!(calibration.map(300, 255), 255);
+        assert_eq!(calibration.map(256, 256), 256);
+    }
+
+    #[test]
+    fn map_spreads() {
+        let calibration = calibration(40, 25);
+        assert_eq!(calibration.map(128, 255), 140);
+        let mut last = 0;
+        for duty in 1..=255 {
+            let mapped = calibration.map(duty, 255);
+            assert!(mapped >= last);
+            last = mapped;
+        }
+    }
+
+    #[test]
+    fn map_without_a_range() {
+        // a fan that needs the full duty to turn is played as is
+        let calibration = calibration(255, 255);
+        assert_eq!(calibration.map(128, 255), 128);
+        assert_eq!(calibration.map(128, 1), 128);
+    }
+}
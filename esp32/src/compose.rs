@@ -0,0 +1,276 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::waveform::Waveform;
+
+/// Deep enough for an envelope over a sum of waves,
+/// shallow enough for the stack of the player thread.
+pub const MAX_DEPTH: usize = 8;
+/// Of `add`, `multiply`, `min` and `max`, each is evaluated every tick.
+pub const MAX_NODES: usize = 16;
+
+/// An expression tree evaluated every tick, e.g. a breathing wave that fades out:
+/// `{"type": "multiply", "nodes": [{"type": "waveform", ...}, {"type": "steps", "steps": [1, 0.5, 0], "interval": 10000, "once": true}]}`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Node {
+    Constant {
+        value: f32,
+    },
+    Steps {
+        steps: Vec<f32>,
+        /// in milliseconds
+        interval: u64,
+        /// hold the last step instead of looping
+        #[serde(default)]
+        once: bool,
+    },
+    Waveform(Waveform),
+    Add {
+        nodes: Vec<Node>,
+    },
+    Multiply {
+        nodes: Vec<Node>,
+    },
+    Min {
+        nodes: Vec<Node>,
+    },
+    Max {
+        nodes: Vec<Node>,
+    },
+    Clamp {
+        node: Box<Node>,
+        min: f32,
+        max: f32,
+    },
+}
+
+impl Node {
+    pub fn value_at(&self, elapsed: u64) -> f32 {
+        match self {
+            Node::Constant { value } => *value,
+            Node::Steps {
+                steps,
+                interval,
+                once,
+            } => {
+                if steps.is_empty() {
+                    return 0.0;
+                }
+                let index = (elapsed / (*interval).max(1)) as usize;
+                if *once {
+                    steps[index.min(steps.len() - 1)]
+                } else {
+                    steps[index % steps.len()]
+                }
+            }
+            Node::Waveform(waveform) => waveform.value_at(elapsed),
+            Node::Add { nodes } => nodes.iter().map(|n| n.value_at(elapsed)).sum(),
+            Node::Multiply { nodes } => nodes.iter().map(|n| n.value_at(elapsed)).product(),
+            Node::Min { nodes } => nodes
+                .iter()
+                .map(|n| n.value_at(elapsed))
+                .reduce(f32::min)
+                .unwrap_or(0.0),
+            Node::Max { nodes } => nodes
+                .iter()
+                .map(|n| n.value_at(elapsed))
+                .reduce(f32::max)
+                .unwrap_or(0.0),
+            Node::Clamp { node, min, max } => node.value_at(elapsed).max(*min).min(*max),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        let children = match self {
+            Node::Add { nodes }
+            | Node::Multiply { nodes }
+            | Node::Min { nodes }
+            | Node::Max { nodes } => nodes.iter().map(|n| n.depth()).max().unwrap_or(0),
+            Node::Clamp { node, .. } => node.depth(),
+            _ => 0,
+        };
+        children + 1
+    }
+
+    /// The JSON nesting of an upload is bounded before it is parsed,
+    /// see `validation::DepthLimit`.
+    pub fn validate(&self) -> Result<()> {
+        if self.depth() > MAX_DEPTH {
+            return Err(anyhow!("composition is deeper than {} levels", MAX_DEPTH));
+        }
+        self.validate_nodes()
+    }
+
+    fn validate_nodes(&self) -> Result<()> {
+        match self {
+            Node::Add { nodes }
+            | Node::Multiply { nodes }
+            | Node::Min { nodes }
+            | Node::Max { nodes } => {
+                if nodes.len() > MAX_NODES {
+                    return Err(anyhow!("a node has more than {} nodes", MAX_NODES));
+                }
+                nodes.iter().try_for_each(|n| n.validate_nodes())
+            }
+            Node::Clamp { node, min, max } => {
+                if min.is_nan() || max.is_nan() || min > max {
+                    return Err(anyhow!("clamp min {} is above max {}", min, max));
+                }
+                node.validate_nodes()
+            }
+            Node::Waveform(waveform) => waveform.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant(value: f32) -> Node {
+        Node::Constant { value }
+    }
+
+    fn steps(steps: &[f32], once: bool) -> Node {
+        Node::Steps {
+            steps: steps.to_vec(),
+            interval: 100,
+            once,
+        }
+    }
+
+    fn clamp(node: Node, min: f32, max: f32) -> Node {
+        Node::Clamp {
+            node: Box::new(node),
+            min,
+            max,
+        }
+    }
+
+    /// `depth` levels of clamps around a constant.
+    fn nested(depth: usize) -> Node {
+        (1..depth).fold(constant(1.0), |node, _| clamp(node, 0.0, 255.0))
+    }
+
+    #[test]
+    fn steps_loop_or_hold() {
+        let looped = steps(&[1.0, 2.0, 3.0], false);
+        let values: Vec<f32> = [0, 99, 100, 250, 300, 450]
+            .iter()
+            .map(|&at| looped.value_at(at))
+            .collect();
+        assert_eq!(values, [1.0, 1.0, 2.0, 3.0, 1.0, 2.0]);
+
+        let once = steps(&[1.0, 2.0, 3.0], true);
+        assert_eq!(once.value_at(250), 3.0);
+        assert_eq!(once.value_at(10_000), 3.0);
+
+        assert_eq!(steps(&[], false).value_at(100), 0.0);
+    }
+
+    #[test]
+    fn operators() {
+        let nodes = || vec![constant(2.0), constant(-3.0), constant(4.0)];
+        assert_eq!(Node::Add { nodes: nodes() }.value_at(0), 3.0);
+        assert_eq!(Node::Multiply { nodes: nodes() }.value_at(0), -24.0);
+        assert_eq!(Node::Min { nodes: nodes() }.value_at(0), -3.0);
+        assert_eq!(Node::Max { nodes: nodes() }.value_at(0), 4.0);
+
+        // no nodes
+        assert_eq!(Node::Add { nodes: vec![] }.value_at(0), 0.0);
+        assert_eq!(Node::Multiply { nodes: vec![] }.value_at(0), 1.0);
+        assert_eq!(Node::Min { nodes: vec![] }.value_at(0), 0.0);
+        assert_eq!(Node::Max { nodes: vec![] }.value_at(0), 0.0);
+    }
+
+    #[test]
+    fn clamped() {
+        assert_eq!(clamp(constant(300.0), 0.0, 255.0).value_at(0), 255.0);
+        assert_eq!(clamp(constant(-5.0), 0.0, 255.0).value_at(0), 0.0);
+        assert_eq!(clamp(constant(128.0), 0.0, 255.0).value_at(0), 128.0);
+        assert_eq!(clamp(constant(128.0), 64.0, 64.0).value_at(0), 64.0);
+    }
+
+    #[test]
+    fn fade_out() {
+        // the example of `Node`, a wave fading out over 3 steps of 10 s
+        let node: Node = serde_json::from_value(serde_json::json!({
+            "type": "multiply",
+            "nodes": [
+                {"type": "waveform", "shape": "square", "amplitude": 100, "offset": 100, "period": 1000},
+                {"type": "steps", "steps": [1, 0.5, 0], "interval": 10000, "once": true}
+            ]
+        }))
+        .unwrap();
+        assert_eq!(node.value_at(0), 200.0);
+        assert_eq!(node.value_at(500), 0.0);
+        assert_eq!(node.value_at(10_000), 100.0);
+        assert_eq!(node.value_at(60_000), 0.0);
+    }
+
+    #[test]
+    fn depth() {
+        assert_eq!(constant(1.0).depth(), 1);
+        assert_eq!(nested(MAX_DEPTH).depth(), MAX_DEPTH);
+        let add = Node::Add {
+            nodes: vec![constant(1.0), nested(3)],
+        };
+        assert_eq!(add.depth(), 4);
+    }
+
+    #[test]
+    fn validate_depth() {
+        assert!(nested(MAX_DEPTH).validate().is_ok());
+        assert!(nested(MAX_DEPTH + 1).validate().is_err());
+    }
+
+    #[test]
+    fn validate_nodes() {
+        let max = Node::Max {
+            nodes: (0..MAX_NODES).map(|_| constant(1.0)).collect(),
+        };
+        assert!(max.validate().is_ok());
+        let too_many = Node::Add {
+            nodes: (0..=MAX_NODES).map(|_| constant(1.0)).collect(),
+        };
+        assert!(too_many.validate().is_err());
+        // below the top
+        let inner = clamp(too_many, 0.0, 255.0);
+        assert!(inner.validate().is_err());
+    }
+
+    #[test]
+    fn validate_clamp() {
+        assert!(clamp(constant(1.0), 0.0, 255.0).validate().is_ok());
+        assert!(clamp(constant(1.0), 64.0, 64.0).validate().is_ok());
+        assert!(clamp(constant(1.0), 255.0, 0.0).validate().is_err());
+        assert!(clamp(constant(1.0), f32::NAN, 255.0).validate().is_err());
+        assert!(clamp(constant(1.0), 0.0, f32::NAN).validate().is_err());
+        // below the top
+        let inner = Node::Min {
+            nodes: vec![constant(1.0), clamp(constant(1.0), 10.0, 5.0)],
+        };
+        assert!(inner.validate().is_err());
+    }
+
+    #[test]
+    fn validate_waveform() {
+        let node = |duty_cycle: f32| -> Node {
+            serde_json::from_value(serde_json::json!({
+                "type": "add",
+                "nodes": [{
+                    "type": "waveform",
+                    "shape": "square",
+                    "amplitude": 100,
+                    "period": 1000,
+                    "dutyCycle": duty_cycle
+                }]
+            }))
+            .unwrap()
+        };
+        assert!(node(0.25).validate().is_ok());
+        assert!(node(1.5).validate().is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// Current sense of the output on an ADC1 pin, e.g. a shunt with an amplifier or a hall sensor.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CurrentConfig {
+    pub gpio: i32,
+    #[serde(rename = "millivoltsPerAmp")]
+    pub millivolts_per_amp: f32,
+    /// millivolts without current, e.g. half the supply of a bidirectional hall sensor
+    #[serde(default)]
+    pub offset: f32,
+    /// a time-over-current curve, the output trips once the current stays above any of them long enough
+    pub trips: Vec<Trip>,
+    #[serde(default)]
+    pub action: Action,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Trip {
+    /// in amperes
+    pub current: f32,
+    /// in milliseconds, 0 trips on the first reading above `current`
+    #[serde(default)]
+    pub duration: u64,
+}
+
+/// What a trip does to the output until it is acknowledged.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Cut,
+    /// the duty is held at or below `duty`
+    Derate { duty: u32 },
+}
+
+impl CurrentConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.millivolts_per_amp == 0.0 {
+            return Err(anyhow!("current millivoltsPerAmp must not be 0"));
+        }
+        if self.trips.is_empty() {
+            return Err(anyhow!("current needs at least one trip"));
+        }
+        if self.trips.iter().any(|trip| trip.current <= 0.0) {
+            return Err(anyhow!("current of a trip must be positive"));
+        }
+        Ok(())
+    }
+
+    /// Amperes of a reading, either direction counts.
+    pub fn amps(&self, millivolts: f32) -> f32 {
+        ((millivolts - self.offset) / self.millivolts_per_amp).abs()
+    }
+
+    /// The highest duty allowed while tripped.
+    pub fn limit(&self) -> u32 {
+        match self.action {
+            Action::Cut => 0,
+            Action::Derate { duty } => duty,
+        }
+    }
+}
+
+/// Times how long the current stays above each trip of a curve.
+#[derive(Debug, Default)]
+pub struct Overcurrent {
+    over_since: Vec<Option<Instant>>,
+}
+
+impl Overcurrent {
+    /// The trip the current stayed above for its whole duration, if any.
+    pub fn update(&mut self, config: &CurrentConfig, amps: f32, now: Instant) -> Option<Trip> {
+        self.over_since.resize(config.trips.len(), None);
+
+        let mut tripped = None;
+        for (trip, since) in config.trips.iter().zip(self.over_since.iter_mut()) {
+            if amps <= trip.current {
+                *since = None;
+                continue;
+            }
+            let since = *since.get_or_insert(now);
+            if now - since >= Duration::from_millis(trip.duration) && tripped.is_none() {
+                tripped = Some(*trip);
+            }
+        }
+        if tripped.is_some() {
+            self.reset();
+        }
+        tripped
+    }
+
+    pub fn reset(&mut self) {
+        self.over_since.clear();
+    }
+}
+
+/// See `GET /current`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct CurrentStatus {
+    /// in amperes, None without a current sense
+    pub current: Option<f32>,
+    /// highest since boot
+    pub peak: Option<f32>,
+    /// the duty is cut or derated until the alarm is acknowledged
+    pub tripped: bool,
+}
+
+pub type Current = Arc<Mutex<CurrentStatus>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: Duration = Duration::from_millis(10);
+
+    /// An ACS712-5A, 5 A at once or 2 A for 3 s.
+    fn config() -> CurrentConfig {
+        CurrentConfig {
+            gpio: 2,
+            millivolts_per_amp: 185.0,
+            offset: 1650.0,
+            trips: vec![
+                Trip {
+                    current: 5.0,
+                    duration: 0,
+                },
+                Trip {
+                    current: 2.0,
+                    duration: 3000,
+                },
+            ],
+            action: Action::Cut,
+        }
+    }
+
+    /// Feeds a sample every 10 ms, the time of the first trip since start.
+    fn run(overcurrent: &mut Overcurrent, samples: &[(f32, u64)]) -> Option<(Trip, Duration)> {
+        let config = config();
+        let start = Instant::now();
+        let mut at = Duration::ZERO;
+        for (amps, duration) in samples {
+            let end = at + Duration::from_millis(*duration);
+            while at < end {
+                if let Some(trip) = overcurrent.update(&config, *amps, start + at) {
+                    return Some((trip, at));
+                }
+                at += SAMPLE;
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn amps_either_direction() {
+        let config = config();
+        assert_eq!(config.amps(1650.0), 0.0);
+        assert!((config.amps(1650.0 + 370.0) - 2.0).abs() < 1e-4);
+        assert!((config.amps(1650.0 - 370.0) - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn limit() {
+        assert_eq!(config().limit(), 0);
+        let config = CurrentConfig {
+            action: Action::Derate { duty: 64 },
+            ..config()
+        };
+        assert_eq!(config.limit(), 64);
+    }
+
+    #[test]
+    fn validate() {
+        assert!(config().validate().is_ok());
+        let no_trips = CurrentConfig {
+            trips: vec![],
+            ..config()
+        };
+        assert!(no_trips.validate().is_err());
+        let zero = CurrentConfig {
+            millivolts_per_amp: 0.0,
+            ..config()
+        };
+        assert!(zero.validate().is_err());
+    }
+
+    #[test]
+    fn below_every_trip() {
+        let mut overcurrent = Overcurrent::default();
+        assert_eq!(run(&mut overcurrent, &[(1.9, 10_000), (2.0, 10_000)]), None);
+    }
+
+    #[test]
+    fn instant_trip_on_the_first_sample() {
+        let mut overcurrent = Overcurrent::default();
+        let (trip, at) = run(&mut overcurrent, &[(1.0, 100), (6.0, 100)]).unwrap();
+        assert_eq!(trip.current, 5.0);
+        assert_eq!(at, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn timed_trip_after_its_duration() {
+        let mut overcurrent = Overcurrent::default();
+        let (trip, at) = run(&mut overcurrent, &[(3.0, 10_000)]).unwrap();
+        assert_eq!(trip.current, 2.0);
+        assert_eq!(at, Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn a_dip_starts_the_timer_over() {
+        let mut overcurrent = Overcurrent::default();
+        let (_, at) = run(&mut overcurrent, &[(3.0, 2500), (1.0, 10), (3.0, 10_000)]).unwrap();
+        assert_eq!(at, Duration::from_millis(2510 + 3000));
+    }
+
+    #[test]
+    fn higher_current_keeps_the_timer() {
+        let mut overcurrent = Overcurrent::default();
+        // above both, yet not long enough for the timed trip to lose its start
+        let (trip, at) = run(&mut overcurrent, &[(3.0, 2000), (4.5, 10_000)]).unwrap();
+        assert_eq!(trip.current, 2.0);
+        assert_eq!(at, Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn starts_over_after_a_trip() {
+        let mut overcurrent = Overcurrent::default();
+        assert!(run(&mut overcurrent, &[(3.0, 10_000)]).is_some());
+        let (_, at) = run(&mut overcurrent, &[(3.0, 10_000)]).unwrap();
+        assert_eq!(at, Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn reset_forgets_the_timers() {
+        let mut overcurrent = Overcurrent::default();
+        assert_eq!(run(&mut overcurrent, &[(3.0, 2000)]), None);
+        overcurrent.reset();
+        let (_, at) = run(&mut overcurrent, &[(3.0, 10_000)]).unwrap();
+        assert_eq!(at, Duration::from_millis(3000));
+    }
+}
@@ -0,0 +1,359 @@
+use anyhow::{anyhow, Result};
+
+use crate::steps::{self, StepOptions};
+
+/// Both axes of the mojs curve editor range from 0 to 100,
+/// with y pointing down, so `M0, 100` is progress 0 with value 0.
+const EDITOR_SIZE: f64 = 100.0;
+
+/// Bisection steps to find t for a given x, 2^-32 of a segment is far below a pixel.
+const SOLVE_ITERATIONS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct Point {
+    x: f64,
+    y: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    p0: Point,
+    p1: Point,
+    p2: Point,
+    p3: Point,
+}
+
+impl Segment {
+    fn at(&self, t: f64) -> Point {
+        let u = 1.0 - t;
+        let a = u * u * u;
+        let b = 3.0 * u * u * t;
+        let c = 3.0 * u * t * t;
+        let d = t * t * t;
+        Point {
+            x: a * self.p0.x + b * self.p1.x + c * self.p2.x + d * self.p3.x,
+            y: a * self.p0.y + b * self.p1.y + c * self.p2.y + d * self.p3.y,
+        }
+    }
+
+    fn contains_x(&self, x: f64) -> bool {
+        let (min, max) = if self.p0.x <= self.p3.x {
+            (self.p0.x, self.p3.x)
+        } else {
+            (self.p3.x, self.p0.x)
+        };
+        x >= min && x <= max
+    }
+
+    /// y at x, assuming x is monotonic along the segment.
+    fn y_at(&self, x: f64) -> f64 {
+        let increasing = self.p3.x >= self.p0.x;
+        let mut low = 0.0;
+        let mut high = 1.0;
+        for _ in 0..SOLVE_ITERATIONS {
+            let mid = (low + high) / 2.0;
+            if (self.at(mid).x < x) == increasing {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        self.at((low + high) / 2.0).y
+    }
+}
+
+/// A path made by the mojs curve editor, e.g. `M0, 100 C0, 100 50, 50 50, 50 C50, 50 100, 0 100, 0`.
+#[derive(Debug, Clone)]
+pub struct Curve {
+    segments: Vec<Segment>,
+}
+
+impl Curve {
+    /// Supports absolute `M`, `L` and `C` commands, which is all the curve editor produces.
+    pub fn parse(path: &str) -> Result<Curve> {
+        let mut tokens = Tokenizer { rest: path };
+
+        let mut segments = vec![];
+        let mut command = None;
+        let mut current: Option<Point> = None;
+
+        while let Some(token) = tokens.peek()? {
+            let command_ = match token {
+                Token::Command(c) => {
+                    tokens.next()?;
+                    command = Some(c);
+                    c
+                }
+                // repeated coordinates reuse the last command, implicit lines after M
+                Token::Number(_) => match command {
+                    Some('M') => 'L',
+                    Some(c) => c,
+                    None => return Err(anyhow!("path must start with a command")),
+                },
+            };
+
+            match command_ {
+                'M' => {
+                    current = Some(tokens.point()?);
+                }
+                'L' => {
+                    let p0 = current.ok_or_else(|| anyhow!("L before M"))?;
+                    let p3 = tokens.point()?;
+                    segments.push(Segment {
+                        p0,
+                        p1: p0,
+                        p2: p3,
+                        p3,
+                    });
+                    current = Some(p3);
+                }
+                'C' => {
+                    let p0 = current.ok_or_else(|| anyhow!("C before M"))?;
+                    let p1 = tokens.point()?;
+                    let p2 = tokens.point()?;
+                    let p3 = tokens.point()?;
+                    segments.push(Segment { p0, p1, p2, p3 });
+                    current = Some(p3);
+                }
+                c => return Err(anyhow!("unsupported path command: {}", c)),
+            }
+        }
+
+        if segments.is_empty() {
+            return Err(anyhow!("path has no segments"));
+        }
+        // `ease` looks x up from left to right, a vertical jump keeps its x
+        let mut x = segments[0].p0.x;
+        for segment in &segments {
+            for end in [segment.p0, segment.p3] {
+                if end.x < x {
+                    return Err(anyhow!("path goes back from x {} to {}", x, end.x));
+                }
+                x = end.x;
+            }
+        }
+
+        Ok(Curve { segments })
+    }
+
+    /// Same as the easing function of the curve editor,
+    /// progress 0..=1 maps to the curve value, normally but not necessarily within 0..=1.
+    pub fn ease(&self, progress: f64) -> f64 {
+        let x = progress * EDITOR_SIZE;
+
+        let first = self.segments.first().unwrap();
+        let last = self.segments.last().unwrap();
+
+        let y = if x <= first.p0.x {
+            first.p0.y
+        } else if x >= last.p3.x {
+            last.p3.y
+        } else {
+            match self.segments.iter().find(|s| s.contains_x(x)) {
+                Some(segment) => segment.y_at(x),
+                // a gap between sub paths, hold the end of the previous one
+                None => {
+                    self.segments
+                        .iter()
+                        .rev()
+                        .find(|s| s.p3.x < x)
+                        .unwrap_or(first)
+                        .p3
+                        .y
+                }
+            }
+        };
+
+        1.0 - y / EDITOR_SIZE
+    }
+}
+
+/// Samples the curve at `path` every `options.interval` ms, the same way the browser does.
+pub fn sample(path: &str, options: &StepOptions) -> Result<Vec<i32>> {
+    let curve = Curve::parse(path)?;
+    let steps = steps::generate(|progress| curve.ease(progress), options)?;
+    Ok(steps::to_duty(&steps))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    Command(char),
+    Number(f64),
+}
+
+struct Tokenizer<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn next(&mut self) -> Result<Option<Token>> {
+        self.rest = self
+            .rest
+            .trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+
+        let mut chars = self.rest.chars();
+        let c = match chars.next() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        if c.is_ascii_alphabetic() {
+            self.rest = chars.as_str();
+            return Ok(Some(Token::Command(c)));
+        }
+
+        let bytes = self.rest.as_bytes();
+        let len = (0..bytes.len())
+            .find(|&i| {
+                let b = bytes[i];
+                let sign = (b == b'-' || b == b'+') && (i == 0 || bytes[i - 1] == b'e');
+                !(b.is_ascii_digit() || b == b'.' || b == b'e' || sign)
+            })
+            .unwrap_or(bytes.len());
+
+        let number = self.rest[..len].parse::<f64>().map_err(|_| {
+            anyhow!(
+                "invalid number in path near: {}",
+                self.rest.chars().take(8).collect::<String>()
+            )
+        })?;
+        self.rest = &self.rest[len..];
+
+        Ok(Some(Token::Number(number)))
+    }
+
+    fn peek(&self) -> Result<Option<Token>> {
+        Tokenizer { rest: self.rest }.next()
+    }
+
+    fn number(&mut self) -> Result<f64> {
+        match self.next()? {
+            Some(Token::Number(n)) => Ok(n),
+            _ => Err(anyhow!("path command is missing coordinates")),
+        }
+    }
+
+    fn point(&mut self) -> Result<Point> {
+        Ok(Point {
+            x: self.number()?,
+            y: self.number()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINEAR: &str = "M0, 100 L100, 0";
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn linear() {
+        let curve = Curve::parse(LINEAR).unwrap();
+        for progress in [0.0, 0.25, 0.5, 1.0] {
+            assert!(close(curve.ease(progress), progress));
+        }
+    }
+
+    #[test]
+    fn editor_default() {
+        // the start path of the curve editor rises to 0.5 at half way and ends at 1
+        let curve = Curve::parse("M0, 100 C0, 100 50, 50 50, 50 C50, 50 100, 0 100, 0").unwrap();
+        assert!(close(curve.ease(0.0), 0.0));
+        assert!(close(curve.ease(0.5), 0.5));
+        assert!(close(curve.ease(1.0), 1.0));
+        assert!(curve.ease(0.25) > 0.0 && curve.ease(0.25) < 0.5);
+    }
+
+    #[test]
+    fn ends() {
+        // a curve not spanning the whole editor holds its ends
+        let curve = Curve::parse("M20, 80 L80, 20").unwrap();
+        assert!(close(curve.ease(0.0), 0.2));
+        assert!(close(curve.ease(0.1), 0.2));
+        assert!(close(curve.ease(0.5), 0.5));
+        assert!(close(curve.ease(0.9), 0.8));
+        assert!(close(curve.ease(1.0), 0.8));
+        // progress outside 0..=1 too
+        assert!(close(curve.ease(-1.0), 0.2));
+        assert!(close(curve.ease(2.0), 0.8));
+    }
+
+    #[test]
+    fn implicit_commands() {
+        // coordinates after M are lines, after C more curves
+        let lines = Curve::parse("M0 100 50 50 100 0").unwrap();
+        assert!(close(lines.ease(0.75), 0.75));
+        let curves = Curve::parse("M0,100 C0,100 50,50 50,50 50,50 100,0 100,0").unwrap();
+        assert!(close(curves.ease(0.5), 0.5));
+    }
+
+    #[test]
+    fn duplicate_x() {
+        // a vertical jump at x 50, the value at 50 is the one before it
+        let curve = Curve::parse("M0, 100 L50, 100 L50, 0 L100, 0").unwrap();
+        assert!(close(curve.ease(0.25), 0.0));
+        assert!(close(curve.ease(0.5), 0.0));
+        assert!(close(curve.ease(0.51), 1.0));
+        assert!(close(curve.ease(1.0), 1.0));
+    }
+
+    #[test]
+    fn out_of_order() {
+        assert!(Curve::parse("M0, 100 L60, 50 L40, 0").is_err());
+        assert!(Curve::parse("M100, 0 L0, 100").is_err());
+        // a new sub path must not start left of the last one
+        assert!(Curve::parse("M0, 100 L50, 50 M40, 50 L100, 0").is_err());
+        assert!(Curve::parse("M0, 100 L40, 50 M60, 50 L100, 0").is_ok());
+    }
+
+    #[test]
+    fn gap_between_sub_paths() {
+        let curve = Curve::parse("M0, 100 L40, 50 M60, 20 L100, 0").unwrap();
+        assert!(close(curve.ease(0.5), 0.5));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Curve::parse("").is_err());
+        assert!(Curve::parse("  , ").is_err());
+        assert!(Curve::parse("M0, 100").is_err());
+        assert!(Curve::parse("0, 100 L100, 0").is_err());
+        assert!(Curve::parse("L100, 0").is_err());
+        assert!(Curve::parse("M0, 100 C10, 90").is_err());
+        assert!(Curve::parse("M0, 100 Q50, 50 100, 0").is_err());
+        assert!(Curve::parse("M0, 100 L1x0, 0").is_err());
+    }
+
+    #[test]
+    fn exponent() {
+        let curve = Curve::parse("M0e0, 1e2 L1e+2, 0e-1").unwrap();
+        assert!(close(curve.ease(0.5), 0.5));
+    }
+
+    #[test]
+    fn sampled() {
+        let options = StepOptions {
+            min_pwm: 0,
+            max_pwm: 255,
+            duration: 1000,
+            interval: 100,
+            precision: 0,
+            max_steps: 2000,
+            reverse: false,
+        };
+        // the steps of a linear easing, see `steps::tests::linear`, but for the floor of
+        // a solved x a hair below the step
+        let linear = [0, 25, 51, 76, 102, 127, 153, 178, 204, 229];
+        let steps = sample(LINEAR, &options).unwrap();
+        assert_eq!(steps.len(), linear.len());
+        for (step, linear) in steps.iter().zip(linear) {
+            assert!((step - linear).abs() <= 1, "{} {}", step, linear);
+        }
+        assert!(sample("", &options).is_err());
+    }
+}
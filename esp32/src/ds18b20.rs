@@ -0,0 +1,163 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::hal::{
+    delay::Ets,
+    gpio::{AnyIOPin, InputOutput, PinDriver, Pull},
+    interrupt,
+};
+
+use crate::{input::Source, thermo};
+
+const SKIP_ROM: u8 = 0xCC;
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xBE;
+
+/// Conversion time at the default 12 bit resolution.
+const CONVERSION_TIME: Duration = Duration::from_millis(750);
+
+/// A bit-banged 1-Wire bus at standard speed on an open-drain pin,
+/// which needs an external pull-up of about 4.7k.
+pub struct OneWire {
+    pin: PinDriver<'static, AnyIOPin, InputOutput>,
+}
+
+impl OneWire {
+    pub fn new(gpio: i32) -> Result<Self> {
+        // the pin number comes from the sensor config, checked by `pins::check_all`
+        let mut pin = PinDriver::input_output_od(unsafe { AnyIOPin::new(gpio) })?;
+        pin.set_pull(Pull::Up)?;
+        pin.set_high()?;
+        Ok(OneWire { pin })
+    }
+
+    /// True if a device answered with a presence pulse.
+    pub fn reset(&mut self) -> Result<bool> {
+        let pin = &mut self.pin;
+        let presence = interrupt::free(|| -> Result<bool> {
+            pin.set_low()?;
+            Ets::delay_us(480);
+            pin.set_high()?;
+            Ets::delay_us(70);
+            let presence = pin.is_low();
+            Ets::delay_us(410);
+            Ok(presence)
+        })?;
+        Ok(presence)
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<()> {
+        let pin = &mut self.pin;
+        interrupt::free(|| -> Result<()> {
+            pin.set_low()?;
+            if bit {
+                Ets::delay_us(6);
+                pin.set_high()?;
+                Ets::delay_us(64);
+            } else {
+                Ets::delay_us(60);
+                pin.set_high()?;
+                Ets::delay_us(10);
+            }
+            Ok(())
+        })
+    }
+
+    fn read_bit(&mut self) -> Result<bool> {
+        let pin = &mut self.pin;
+        interrupt::free(|| -> Result<bool> {
+            pin.set_low()?;
+            Ets::delay_us(6);
+            pin.set_high()?;
+            Ets::delay_us(9);
+            let bit = pin.is_high();
+            Ets::delay_us(55);
+            Ok(bit)
+        })
+    }
+
+    pub fn write_byte(&mut self, byte: u8) -> Result<()> {
+        for i in 0..8 {
+            self.write_bit((byte >> i) & 1 == 1)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8> {
+        let mut byte = 0;
+        for i in 0..8 {
+            if self.read_bit()? {
+                byte |= 1 << i;
+            }
+        }
+        Ok(byte)
+    }
+}
+
+/// The only DS18B20 on a 1-Wire bus, addressed with skip ROM.
+pub struct Ds18b20 {
+    bus: OneWire,
+    /// of the conversion in progress
+    converting_since: Option<Instant>,
+}
+
+pub fn new(gpio: i32) -> Result<Ds18b20> {
+    let mut bus = OneWire::new(gpio)?;
+    if !bus.reset()? {
+        return Err(anyhow!("no 1-Wire device on GPIO{}", gpio));
+    }
+    Ok(Ds18b20 {
+        bus,
+        converting_since: None,
+    })
+}
+
+impl Ds18b20 {
+    /// Starts a conversion, read it once `CONVERSION_TIME` passed.
+    pub fn convert(&mut self) -> Result<Duration> {
+        self.command(CONVERT_T)?;
+        self.converting_since = Some(Instant::now());
+        Ok(CONVERSION_TIME)
+    }
+
+    /// In celsius, blocks for the rest of a conversion, or for a whole one if none was started.
+    pub fn read(&mut self) -> Result<f32> {
+        let since = match self.converting_since.take() {
+            Some(since) => since,
+            None => {
+                self.convert()?;
+                self.converting_since.take().unwrap()
+            }
+        };
+        thread::sleep(CONVERSION_TIME.saturating_sub(since.elapsed()));
+
+        self.command(READ_SCRATCHPAD)?;
+        let mut scratchpad = [0u8; 9];
+        for byte in scratchpad.iter_mut() {
+            *byte = self.bus.read_byte()?;
+        }
+
+        thermo::ds18b20_celsius(&scratchpad).map_err(|e| anyhow!("DS18B20: {}", e))
+    }
+
+    fn command(&mut self, command: u8) -> Result<()> {
+        if !self.bus.reset()? {
+            return Err(anyhow!("DS18B20 is gone"));
+        }
+        self.bus.write_byte(SKIP_ROM)?;
+        self.bus.write_byte(command)
+    }
+}
+
+impl Source for Ds18b20 {
+    fn read(&mut self) -> Result<f32> {
+        Ds18b20::read(self)
+    }
+
+    fn start(&mut self) -> Result<Duration> {
+        self.convert()
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    ffi::c_void,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU8, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::sys::{
+    gpio_config, gpio_config_t, gpio_get_level, gpio_install_isr_service, gpio_int_type_t,
+    gpio_int_type_t_GPIO_INTR_ANYEDGE, gpio_int_type_t_GPIO_INTR_POSEDGE, gpio_isr_handler_add,
+    gpio_isr_handler_remove, gpio_mode_t_GPIO_MODE_INPUT, gpio_pulldown_t_GPIO_PULLDOWN_DISABLE,
+    gpio_pullup_t_GPIO_PULLUP_ENABLE, ESP_ERR_INVALID_STATE, ESP_OK,
+};
+use log::error;
+use serde::Serialize;
+
+use crate::{
+    angle::{self, EncoderConfig},
+    esp32, pins,
+};
+
+/// Updated by the GPIO interrupts of the encoder.
+pub struct Counter {
+    config: EncoderConfig,
+    count: AtomicI32,
+    /// levels of both channels, `a << 1 | b`
+    levels: AtomicU8,
+    homed: AtomicBool,
+}
+
+/// See `GET /encoder`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Position {
+    pub count: i32,
+    /// in degrees
+    pub angle: f32,
+    /// the index was found, always without an index pin
+    pub homed: bool,
+}
+
+impl Counter {
+    pub fn position(&self) -> Position {
+        let count = self.count.load(Ordering::Relaxed);
+        Position {
+            count,
+            angle: angle::degrees(count, self.config.counts_per_rev),
+            homed: self.config.index.is_none() || self.homed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+unsafe fn levels(config: &EncoderConfig) -> u8 {
+    ((gpio_get_level(config.a) as u8) << 1) | gpio_get_level(config.b) as u8
+}
+
+unsafe extern "C" fn on_edge(arg: *mut c_void) {
+    let counter = &*(arg as *const Counter);
+    let current = levels(&counter.config);
+    let previous = counter.levels.swap(current, Ordering::Relaxed);
+    counter
+        .count
+        .fetch_add(angle::quadrature(previous, current), Ordering::Relaxed);
+}
+
+unsafe extern "C" fn on_index(arg: *mut c_void) {
+    let counter = &*(arg as *const Counter);
+    counter.count.store(0, Ordering::Relaxed);
+    counter.homed.store(true, Ordering::Relaxed);
+}
+
+/// The ESP32-C3 has no pulse counter, both channels are decoded by GPIO interrupts on every edge,
+/// and the count is reset on each rising edge of the index.
+pub struct Encoder {
+    counter: Arc<Counter>,
+    /// the reference handed to the interrupts
+    arg: *const Counter,
+}
+
+unsafe impl Send for Encoder {}
+
+unsafe fn subscribe(
+    gpio: i32,
+    intr_type: gpio_int_type_t,
+    handler: unsafe extern "C" fn(*mut c_void),
+    arg: *const Counter,
+) -> Result<()> {
+    // the mask below is only defined for a pin of the chip
+    pins::check("encoder", gpio)?;
+    let io_config = gpio_config_t {
+        pin_bit_mask: 1u64 << gpio,
+        mode: gpio_mode_t_GPIO_MODE_INPUT,
+        pull_up_en: gpio_pullup_t_GPIO_PULLUP_ENABLE,
+        pull_down_en: gpio_pulldown_t_GPIO_PULLDOWN_DISABLE,
+        intr_type,
+        ..Default::default()
+    };
+    let res = gpio_config(&io_config);
+    if res != ESP_OK {
+        error!(
+            "Failed to config encoder GPIO{}: {}",
+            gpio,
+            esp32::esp_err_to_str(res)
+        );
+        return Err(anyhow!("Failed to config encoder GPIO{}", gpio));
+    }
+
+    let res = gpio_isr_handler_add(gpio, Some(handler), arg as *mut c_void);
+    if res != ESP_OK {
+        error!(
+            "Failed to add encoder handler of GPIO{}: {}",
+            gpio,
+            esp32::esp_err_to_str(res)
+        );
+        return Err(anyhow!("Failed to add encoder handler"));
+    }
+    Ok(())
+}
+
+pub fn new(config: &EncoderConfig) -> Result<Encoder> {
+    unsafe {
+        // installed already if anything else subscribed to a pin
+        let res = gpio_install_isr_service(0);
+        if res != ESP_OK && res != ESP_ERR_INVALID_STATE {
+            error!(
+                "Failed to install GPIO ISR service: {}",
+                esp32::esp_err_to_str(res)
+            );
+            return Err(anyhow!("Failed to install GPIO ISR service"));
+        }
+
+        let counter = Arc::new(Counter {
+            config: config.clone(),
+            count: AtomicI32::new(0),
+            levels: AtomicU8::new(0),
+            homed: AtomicBool::new(false),
+        });
+        let encoder = Encoder {
+            arg: Arc::into_raw(Arc::clone(&counter)),
+            counter,
+        };
+
+        // dropping the encoder removes the handlers added so far
+        subscribe(
+            config.a,
+            gpio_int_type_t_GPIO_INTR_ANYEDGE,
+            on_edge,
+            encoder.arg,
+        )?;
+        subscribe(
+            config.b,
+            gpio_int_type_t_GPIO_INTR_ANYEDGE,
+            on_edge,
+            encoder.arg,
+        )?;
+        if let Some(index) = config.index {
+            subscribe(
+                index,
+                gpio_int_type_t_GPIO_INTR_POSEDGE,
+                on_index,
+                encoder.arg,
+            )?;
+        }
+        encoder
+            .counter
+            .levels
+            .store(levels(config), Ordering::Relaxed);
+
+        Ok(encoder)
+    }
+}
+
+impl Encoder {
+    pub fn position(&self) -> Position {
+        self.counter.position()
+    }
+
+    /// Shared with the HTTP handlers.
+    pub fn counter(&self) -> Arc<Counter> {
+        Arc::clone(&self.counter)
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        let config = &self.counter.config;
+        unsafe {
+            gpio_isr_handler_remove(config.a);
+            gpio_isr_handler_remove(config.b);
+            if let Some(index) = config.index {
+                gpio_isr_handler_remove(index);
+            }
+            drop(Arc::from_raw(self.arg));
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::pins;
+
+/// The tachometer of the fan on the output and what to do when it stalls.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TachConfig {
+    pub gpio: i32,
+    /// most PC fans pulse twice a revolution
+    #[serde(rename = "pulsesPerRev", default = "default_pulses_per_rev")]
+    pub pulses_per_rev: u32,
+    /// seconds of a nonzero duty without revolutions before the fan counts as stalled
+    #[serde(rename = "stallTimeout", default = "default_stall_timeout")]
+    pub stall_timeout: u64,
+    /// duty of a kick-start pulse
+    #[serde(rename = "kickDuty", default = "default_kick_duty")]
+    pub kick_duty: u32,
+    /// in milliseconds
+    #[serde(rename = "kickDuration", default = "default_kick_duration")]
+    pub kick_duration: u64,
+    /// kick-starts before the alarm is raised
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+fn default_pulses_per_rev() -> u32 {
+    2
+}
+
+fn default_stall_timeout() -> u64 {
+    3
+}
+
+fn default_kick_duty() -> u32 {
+    255
+}
+
+fn default_kick_duration() -> u64 {
+    1000
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+impl TachConfig {
+    pub fn validate(&self) -> Result<()> {
+        pins::check("tach", self.gpio)?;
+        if self.pulses_per_rev == 0 {
+            return Err(anyhow!("tach pulsesPerRev must not be 0"));
+        }
+        if self.stall_timeout == 0 {
+            return Err(anyhow!("tach stallTimeout must not be 0"));
+        }
+        Ok(())
+    }
+}
+
+/// Revolutions per minute of `pulses` counted in `elapsed`.
+pub fn rpm(pulses: u32, pulses_per_rev: u32, elapsed: Duration) -> f32 {
+    let seconds = elapsed.as_secs_f32();
+    if seconds <= 0.0 || pulses_per_rev == 0 {
+        return 0.0;
+    }
+    pulses as f32 * 60.0 / (pulses_per_rev as f32 * seconds)
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    /// no duty, nothing to watch
+    #[default]
+    Idle,
+    Running,
+    /// duty without revolutions, not for long enough yet
+    Stalling,
+    Kicking,
+    /// kick-starts did not help, the alarm is raised and kick-starts stop until acknowledged
+    Stalled,
+}
+
+/// Watches duty against revolutions, and kick-starts a fan that does not turn.
+#[derive(Debug, Default)]
+pub struct Stall {
+    state: State,
+    since: Option<Instant>,
+    kicks: u32,
+}
+
+impl Stall {
+    /// The duty to play instead of `duty` while kicking.
+    pub fn update(
+        &mut self,
+        config: &TachConfig,
+        duty: u32,
+        rpm: Option<f32>,
+        now: Instant,
+    ) -> Option<u32> {
+        let stall_timeout = Duration::from_secs(config.stall_timeout);
+        let kick_duration = Duration::from_millis(config.kick_duration);
+        let since = self.since.unwrap_or(now);
+
+        let (state, kick) = match (self.state, rpm) {
+            (_, _) if duty == 0 => (State::Idle, None),
+            // not measured yet
+            (State::Idle, None) => (State::Idle, None),
+            (_, Some(rpm)) if rpm > 0.0 && self.state != State::Kicking => (State::Running, None),
+            (State::Idle | State::Running, _) => (State::Stalling, None),
+            (State::Stalling, _) if now - since < stall_timeout => (State::Stalling, None),
+            (State::Stalling, _) if self.kicks < config.retries => {
+                (State::Kicking, Some(config.kick_duty))
+            }
+            (State::Stalling, _) => (State::Stalled, None),
+            (State::Kicking, _) if now - since < kick_duration => {
+                (State::Kicking, Some(config.kick_duty))
+            }
+            // give it the stall timeout again to show revolutions at the normal duty
+            (State::Kicking, _) => (State::Stalling, None),
+            (State::Stalled, _) => (State::Stalled, None),
+        };
+
+        if state != self.state {
+            self.since = Some(now);
+            match state {
+                State::Kicking => self.kicks += 1,
+                State::Idle | State::Running => self.kicks = 0,
+                _ => {}
+            }
+        }
+        self.state = state;
+        kick
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn kicks(&self) -> u32 {
+        self.kicks
+    }
+}
+
+/// See `GET /fan`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct FanStatus {
+    /// None without a tachometer, or before the first measurement
+    pub rpm: Option<f32>,
+    /// played, a kick included
+    pub duty: u32,
+    pub state: State,
+    /// kick-starts since the fan last turned
+    pub kicks: u32,
+    /// the stall alarm is latched, see `GET /alarms`
+    pub alarm: bool,
+}
+
+pub type Fan = Arc<Mutex<FanStatus>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: Duration = Duration::from_millis(100);
+
+    fn tach(gpio: i32) -> TachConfig {
+        serde_json::from_value(serde_json::json!({ "gpio": gpio })).unwrap()
+    }
+
+    /// Feeds a duty and rpm every 100 ms, each state change with its time since start in ms
+    /// and the duty kicked then.
+    fn run(
+        stall: &mut Stall,
+        samples: &[(u32, Option<f32>, u64)],
+    ) -> Vec<(u64, State, Option<u32>)> {
+        // 3 s stall timeout, 3 kicks at 255 for 1 s
+        let config = tach(1);
+        let start = Instant::now();
+        let mut at = Duration::ZERO;
+        let mut changes = vec![];
+        for (duty, rpm, duration) in samples {
+            let end = at + Duration::from_millis(*duration);
+            while at < end {
+                let state = stall.state();
+                let kick = stall.update(&config, *duty, *rpm, start + at);
+                if stall.state() != state {
+                    changes.push((at.as_millis() as u64, stall.state(), kick));
+                }
+                at += SAMPLE;
+            }
+        }
+        changes
+    }
+
+    const TURNING: Option<f32> = Some(1200.0);
+    const STILL: Option<f32> = Some(0.0);
+
+    #[test]
+    fn not_measured_yet() {
+        let mut stall = Stall::default();
+        assert_eq!(run(&mut stall, &[(128, None, 10_000)]), []);
+        assert_eq!(stall.state(), State::Idle);
+    }
+
+    #[test]
+    fn running() {
+        let mut stall = Stall::default();
+        let changes = run(&mut stall, &[(128, TURNING, 10_000), (0, TURNING, 1000)]);
+        assert_eq!(
+            changes,
+            [(0, State::Running, None), (10_000, State::Idle, None)]
+        );
+    }
+
+    #[test]
+    fn no_duty_is_no_stall() {
+        let mut stall = Stall::default();
+        assert_eq!(run(&mut stall, &[(0, STILL, 10_000)]), []);
+    }
+
+    #[test]
+    fn kick_after_the_stall_timeout() {
+        let mut stall = Stall::default();
+        let changes = run(
+            &mut stall,
+            &[
+                (128, TURNING, 1000),
+                (128, STILL, 3500),
+                (128, TURNING, 2000),
+            ],
+        );
+        assert_eq!(
+            changes,
+            [
+                (0, State::Running, None),
+                (1000, State::Stalling, None),
+                (4000, State::Kicking, Some(255)),
+                // turning during the kick does not cut it short
+                (5000, State::Stalling, None),
+                (5100, State::Running, None),
+            ]
+        );
+        assert_eq!(stall.kicks(), 0);
+    }
+
+    #[test]
+    fn kicks_until_the_duration() {
+        let mut stall = Stall::default();
+        let config = tach(1);
+        let start = Instant::now();
+        stall.update(&config, 128, STILL, start);
+        assert_eq!(
+            stall.update(&config, 128, STILL, start + Duration::from_secs(3)),
+            Some(255)
+        );
+        assert_eq!(
+            stall.update(&config, 128, TURNING, start + Duration::from_millis(3999)),
+            Some(255)
+        );
+        assert_eq!(
+            stall.update(&config, 128, TURNING, start + Duration::from_secs(4)),
+            None
+        );
+    }
+
+    #[test]
+    fn gives_up_after_the_retries() {
+        let mut stall = Stall::default();
+        let changes = run(&mut stall, &[(128, TURNING, 1000), (128, STILL, 30_000)]);
+        assert_eq!(
+            changes,
+            [
+                (0, State::Running, None),
+                (1000, State::Stalling, None),
+                (4000, State::Kicking, Some(255)),
+                (5000, State::Stalling, None),
+                (8000, State::Kicking, Some(255)),
+                (9000, State::Stalling, None),
+                (12_000, State::Kicking, Some(255)),
+                (13_000, State::Stalling, None),
+                (16_000, State::Stalled, None),
+            ]
+        );
+        assert_eq!(stall.kicks(), 3);
+
+        // no duty starts over
+        let changes = run(&mut stall, &[(0, STILL, 100)]);
+        assert_eq!(changes, [(0, State::Idle, None)]);
+        assert_eq!(stall.kicks(), 0);
+    }
+
+    #[test]
+    fn no_retries() {
+        let mut stall = Stall::default();
+        let mut config = tach(1);
+        config.retries = 0;
+        let start = Instant::now();
+        stall.update(&config, 128, STILL, start);
+        assert_eq!(stall.state(), State::Stalling);
+        assert_eq!(
+            stall.update(&config, 128, STILL, start + Duration::from_secs(3)),
+            None
+        );
+        assert_eq!(stall.state(), State::Stalled);
+    }
+
+    #[test]
+    fn tach_pin() {
+        assert!(tach(1).validate().is_ok());
+        assert!(tach(-1).validate().is_err());
+        assert!(tach(64).validate().is_err());
+        assert!(tach(1000).validate().is_err());
+        assert!(tach(12).validate().is_err());
+        // the output of either board
+        assert!(tach(3).validate().is_err());
+    }
+}
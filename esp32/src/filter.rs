@@ -0,0 +1,247 @@
+use std::collections::VecDeque;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_MEDIAN_WINDOW: usize = 15;
+
+/// A stage of the filter chain of a sensor, applied in order to each raw sample.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FilterConfig {
+    /// exponential moving average, `weight` of a new sample, 1 keeps raw samples
+    Ema { weight: f32 },
+    /// median of the last `window` samples, drops single spikes
+    Median { window: usize },
+    /// limits the change to `max` per second, e.g. a glitching sensor can not jump the fan to full
+    Rate { max: f32 },
+}
+
+impl FilterConfig {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            FilterConfig::Ema { weight } if *weight <= 0.0 || *weight > 1.0 => {
+                Err(anyhow!("ema weight must be in (0, 1]"))
+            }
+            FilterConfig::Median { window } if *window == 0 || *window > MAX_MEDIAN_WINDOW => {
+                Err(anyhow!("median window must be 1 to {}", MAX_MEDIAN_WINDOW))
+            }
+            FilterConfig::Rate { max } if *max <= 0.0 => {
+                Err(anyhow!("rate max must be greater than 0"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+enum Stage {
+    Ema {
+        weight: f32,
+        value: Option<f32>,
+    },
+    Median {
+        window: usize,
+        samples: VecDeque<f32>,
+    },
+    Rate {
+        max: f32,
+        value: Option<f32>,
+    },
+}
+
+impl Stage {
+    fn update(&mut self, x: f32, seconds: f32) -> f32 {
+        match self {
+            Stage::Ema { weight, value } => {
+                let next = match *value {
+                    Some(value) => value + (x - value) * *weight,
+                    None => x,
+                };
+                *value = Some(next);
+                next
+            }
+            Stage::Median { window, samples } => {
+                if samples.len() >= *window {
+                    samples.pop_front();
+                }
+                samples.push_back(x);
+
+                let mut sorted: Vec<f32> = samples.iter().copied().collect();
+                sorted.sort_by(|a, b| a.total_cmp(b));
+                let middle = sorted.len() / 2;
+                if sorted.len() % 2 == 0 {
+                    (sorted[middle - 1] + sorted[middle]) / 2.0
+                } else {
+                    sorted[middle]
+                }
+            }
+            Stage::Rate { max, value } => {
+                let next = match *value {
+                    Some(value) => {
+                        let step = *max * seconds;
+                        x.clamp(value - step, value + step)
+                    }
+                    None => x,
+                };
+                *value = Some(next);
+                next
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            Stage::Ema { value, .. } | Stage::Rate { value, .. } => *value = None,
+            Stage::Median { samples, .. } => samples.clear(),
+        }
+    }
+}
+
+/// The filters of a sensor with their state.
+pub struct Chain {
+    stages: Vec<Stage>,
+}
+
+impl Chain {
+    pub fn new(configs: &[FilterConfig]) -> Self {
+        Chain {
+            stages: configs
+                .iter()
+                .map(|config| match config {
+                    FilterConfig::Ema { weight } => Stage::Ema {
+                        weight: weight.clamp(0.0, 1.0),
+                        value: None,
+                    },
+                    FilterConfig::Median { window } => {
+                        let window = (*window).clamp(1, MAX_MEDIAN_WINDOW);
+                        Stage::Median {
+                            window,
+                            samples: VecDeque::with_capacity(window),
+                        }
+                    }
+                    FilterConfig::Rate { max } => Stage::Rate {
+                        max: max.abs(),
+                        value: None,
+                    },
+                })
+                .collect(),
+        }
+    }
+
+    /// `seconds` since the previous sample.
+    pub fn update(&mut self, raw: f32, seconds: f32) -> f32 {
+        self.stages
+            .iter_mut()
+            .fold(raw, |x, stage| stage.update(x, seconds))
+    }
+
+    /// Forgets the history, e.g. after the sensor was gone for a while.
+    pub fn reset(&mut self) {
+        self.stages.iter_mut().for_each(Stage::reset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A DS18B20 sampled every second, with a glitch to 85, its power-on value.
+    const TRACE: [f32; 10] = [25.0, 25.2, 24.9, 25.1, 85.0, 25.0, 25.3, 25.1, 24.8, 25.2];
+
+    fn run(configs: &[FilterConfig]) -> Vec<f32> {
+        let mut chain = Chain::new(configs);
+        TRACE.iter().map(|raw| chain.update(*raw, 1.0)).collect()
+    }
+
+    fn close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-3,
+                "{:?} is not {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn no_filters() {
+        assert_eq!(run(&[]), TRACE);
+    }
+
+    #[test]
+    fn ema() {
+        close(
+            &run(&[FilterConfig::Ema { weight: 0.5 }]),
+            &[
+                25.0, 25.1, 25.0, 25.05, 55.025, 40.0125, 32.6562, 28.8781, 26.8391, 26.0195,
+            ],
+        );
+        assert_eq!(run(&[FilterConfig::Ema { weight: 1.0 }]), TRACE);
+    }
+
+    #[test]
+    fn median_drops_the_spike() {
+        close(
+            &run(&[FilterConfig::Median { window: 3 }]),
+            &[25.0, 25.1, 25.0, 25.1, 25.1, 25.1, 25.3, 25.1, 25.1, 25.1],
+        );
+        close(
+            &run(&[FilterConfig::Median { window: 4 }]),
+            &[
+                25.0, 25.1, 25.0, 25.05, 25.15, 25.05, 25.2, 25.2, 25.05, 25.15,
+            ],
+        );
+        assert_eq!(run(&[FilterConfig::Median { window: 1 }]), TRACE);
+    }
+
+    #[test]
+    fn rate_limits_the_spike() {
+        close(
+            &run(&[FilterConfig::Rate { max: 1.0 }]),
+            &[25.0, 25.2, 24.9, 25.1, 26.1, 25.1, 25.3, 25.1, 24.8, 25.2],
+        );
+
+        let mut chain = Chain::new(&[FilterConfig::Rate { max: 1.0 }]);
+        assert_eq!(chain.update(25.0, 0.1), 25.0);
+        close(&[chain.update(85.0, 0.1)], &[25.1]);
+        close(&[chain.update(0.0, 0.5)], &[24.6]);
+    }
+
+    #[test]
+    fn chain_applies_in_order() {
+        close(
+            &run(&[
+                FilterConfig::Median { window: 3 },
+                FilterConfig::Ema { weight: 0.5 },
+            ]),
+            &[
+                25.0, 25.05, 25.025, 25.0625, 25.0813, 25.0906, 25.1953, 25.1477, 25.1238, 25.1119,
+            ],
+        );
+    }
+
+    #[test]
+    fn reset_forgets() {
+        let mut chain = Chain::new(&[
+            FilterConfig::Median { window: 3 },
+            FilterConfig::Ema { weight: 0.5 },
+        ]);
+        chain.update(85.0, 1.0);
+        chain.update(85.0, 1.0);
+        chain.reset();
+        assert_eq!(chain.update(25.0, 1.0), 25.0);
+    }
+
+    #[test]
+    fn validate() {
+        assert!(FilterConfig::Ema { weight: 0.3 }.validate().is_ok());
+        assert!(FilterConfig::Ema { weight: 0.0 }.validate().is_err());
+        assert!(FilterConfig::Ema { weight: 1.5 }.validate().is_err());
+        assert!(FilterConfig::Median { window: 15 }.validate().is_ok());
+        assert!(FilterConfig::Median { window: 0 }.validate().is_err());
+        assert!(FilterConfig::Median { window: 16 }.validate().is_err());
+        assert!(FilterConfig::Rate { max: 0.0 }.validate().is_err());
+    }
+}
@@ -1,30 +1,236 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
 
 use anyhow::Result;
 use esp_idf_svc::{
     hal::io::Write,
     http::server::{EspHttpConnection, Request},
-    sys::{
-        soc_module_clk_t_SOC_MOD_CLK_XTAL, temperature_sensor_config_t, temperature_sensor_enable,
-        temperature_sensor_get_celsius, temperature_sensor_handle_t, temperature_sensor_install,
-        ESP_OK,
-    },
 };
-use log::error;
+use log::{error, info};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::esp32;
+use crate::{
+    alarm::{Alarms, Kind},
+    autotune::{self, Autotune},
+    binary,
+    calibration::{self, Calibrate},
+    current::Current,
+    encoder::Counter,
+    fan::Fan,
+    input::{self, Push, RemoteValues},
+    pid::Gains,
+    profile,
+    protection::{self, Protection, ProtectionConfig},
+    pwm_loop::Transport,
+    revision::{self, Applied, Origin, Revision},
+    sensor::{self, Readings},
+    sensor_config::{self, SensorsConfig},
+    storage::{self, PwmConfig},
+    validation::{DepthLimit, Invalid, MAX_NESTING},
+};
 
 static INDEX_HTML_GZ: &[u8] = include_bytes!("./assets/index.html.gz");
 static FAVICON_PNG: &[u8] = include_bytes!("./assets/fan.png");
 
-pub fn new_error_handler<'a>(
-    message: &'a str,
-) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> + 'a {
-    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
-        req.into_response(500, None, &[])?
-            .write_all(message.as_bytes())?;
-        Ok(())
+/// Bodies of the small JSON endpoints.
+pub const MAX_BODY: usize = 8 * 1024;
+/// A config is parsed as it arrives, see `read_config`.
+pub const MAX_CONFIG_BODY: usize = 64 * 1024;
+
+fn content_length(req: &Request<&mut EspHttpConnection<'_>>) -> usize {
+    req.header("Content-Length")
+        .unwrap_or("0")
+        .parse::<usize>()
+        .unwrap_or(0)
+}
+
+fn body_too_large(limit: usize) -> Invalid {
+    Invalid::too_large("body", format!("more than {} bytes", limit))
+}
+
+/// The body of a request as `io::Read`, failing once more than `limit` bytes arrived.
+pub struct Body<'a, 'b, 'c> {
+    req: &'a mut Request<&'b mut EspHttpConnection<'c>>,
+    limit: usize,
+    read: usize,
+    exceeded: bool,
+}
+
+impl<'a, 'b, 'c> Body<'a, 'b, 'c> {
+    pub fn new(req: &'a mut Request<&'b mut EspHttpConnection<'c>>, limit: usize) -> Self {
+        Body {
+            req,
+            limit,
+            read: 0,
+            exceeded: false,
+        }
+    }
+
+    /// A failed read was cut at the limit.
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl io::Read for Body<'_, '_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self
+            .req
+            .read(buf)
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
+        self.read += bytes_read;
+        if self.read > self.limit {
+            self.exceeded = true;
+            return Err(io::Error::other("body too large"));
+        }
+        Ok(bytes_read)
+    }
+}
+
+/// The whole body, up to `MAX_BODY` and `MAX_NESTING`.
+/// Content-Length is only trusted to reject a body early, never to allocate.
+pub fn read_body(
+    req: &mut Request<&mut EspHttpConnection<'_>>,
+) -> Result<Result<Vec<u8>, Invalid>> {
+    if content_length(req) > MAX_BODY {
+        return Ok(Err(body_too_large(MAX_BODY)));
+    }
+
+    let mut buffer = vec![];
+    let mut body = Body::new(req, MAX_BODY);
+    let mut nesting = DepthLimit::new(&mut body, MAX_NESTING);
+    if let Err(e) = io::Read::read_to_end(&mut nesting, &mut buffer) {
+        if nesting.exceeded() {
+            return Ok(Err(nesting.too_deep()));
+        }
+        if body.exceeded() {
+            return Ok(Err(body_too_large(MAX_BODY)));
+        }
+        return Err(e.into());
+    }
+
+    Ok(Ok(buffer))
+}
+
+/// Parses a config as it arrives, up to `MAX_CONFIG_BODY` and `MAX_NESTING`,
+/// so the JSON text of a big curve is never held in RAM next to its steps.
+pub fn read_config<T: DeserializeOwned>(
+    req: &mut Request<&mut EspHttpConnection<'_>>,
+) -> Result<T, Invalid> {
+    if content_length(req) > MAX_CONFIG_BODY {
+        return Err(body_too_large(MAX_CONFIG_BODY));
+    }
+
+    let mut body = Body::new(req, MAX_CONFIG_BODY);
+    let mut nesting = DepthLimit::new(&mut body, MAX_NESTING);
+    // serde_json reads a byte at a time
+    let parsed = serde_json::from_reader(io::BufReader::with_capacity(512, &mut nesting));
+    if parsed.is_err() && nesting.exceeded() {
+        return Err(nesting.too_deep());
+    }
+    parsed.map_err(|e| {
+        if body.exceeded() {
+            body_too_large(MAX_CONFIG_BODY)
+        } else {
+            Invalid::malformed(&e)
+        }
+    })
+}
+
+/// Values are returned as is, without percent-decoding.
+pub fn query_param<'a>(uri: &'a str, key: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+pub fn write_text(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    message: &str,
+) -> Result<()> {
+    req.into_response(
+        status,
+        None,
+        &[("Content-type", "text/plain; charset=UTF-8")],
+    )?
+    .write_all(message.as_bytes())?;
+    Ok(())
+}
+
+pub fn write_json<T: Serialize>(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    value: &T,
+) -> Result<()> {
+    req.into_response(
+        status,
+        None,
+        &[("Content-type", "application/json; charset=UTF-8")],
+    )?
+    .write_all(&serde_json::to_vec(value)?)?;
+    Ok(())
+}
+
+/// Answers a rejected config with its status and a JSON body naming the field.
+pub fn write_invalid(req: Request<&mut EspHttpConnection<'_>>, invalid: &Invalid) -> Result<()> {
+    info!("config rejected: {}", invalid);
+    write_json(req, invalid.status(), invalid)
+}
+
+fn read_steps(req: &mut Request<&mut EspHttpConnection<'_>>) -> Result<PwmConfig, Invalid> {
+    if content_length(req) > MAX_CONFIG_BODY {
+        return Err(body_too_large(MAX_CONFIG_BODY));
+    }
+
+    let mut body = Body::new(req, MAX_CONFIG_BODY);
+    let decoded = binary::read(
+        io::BufReader::with_capacity(512, &mut body),
+        storage::step_limit(),
+    );
+    match decoded {
+        Ok(decoded) => Ok(PwmConfig {
+            steps: decoded.steps,
+            interval: decoded.interval,
+            ..Default::default()
+        }),
+        Err(_) if body.exceeded() => Err(body_too_large(MAX_CONFIG_BODY)),
+        Err(invalid) => Err(invalid),
+    }
+}
+
+/// An upload of `POST /pwm`, JSON or the packed steps of `binary` by Content-Type.
+pub fn read_pwm_config(
+    req: &mut Request<&mut EspHttpConnection<'_>>,
+) -> Result<PwmConfig, Invalid> {
+    let content_type = req.header("Content-Type").unwrap_or("");
+    if content_type.starts_with("application/octet-stream") {
+        read_steps(req)
+    } else {
+        read_config(req)
+    }
+}
+
+/// Samples the curve of an uploaded config into steps and validates it.
+pub fn prepare_config(config: &mut PwmConfig) -> Result<(), Invalid> {
+    if let Some(curve) = &config.curve {
+        let limit = storage::step_limit();
+        if curve.max_steps > limit {
+            return Err(Invalid::too_large(
+                "curve.maxSteps",
+                format!("more than {} steps fit in the free heap", limit),
+            ));
+        }
     }
+    config
+        .fill_steps()
+        .map_err(|e| Invalid::unplayable("curve", e))?;
+    config.validate()
 }
 
 pub fn handle_index(req: Request<&mut EspHttpConnection<'_>>) -> Result<()> {
@@ -54,48 +260,521 @@ pub fn handle_favicon(req: Request<&mut EspHttpConnection<'_>>) -> Result<()> {
     Ok(())
 }
 
-struct WrapperType(temperature_sensor_handle_t);
+pub fn new_sensors_handler(
+    readings: Readings,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        write_json(req, 200, &sensor::status(&readings))
+    }
+}
+
+pub fn handle_sensors_config_get(req: Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    write_json(req, 200, &sensor_config::get()?)
+}
+
+/// External sensors are opened at boot, a saved config applies after a restart.
+pub fn handle_sensors_config_put(mut req: Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    let buffer = match read_body(&mut req)? {
+        Ok(buffer) => buffer,
+        Err(invalid) => return write_invalid(req, &invalid),
+    };
+
+    let config: SensorsConfig = match serde_json::from_slice(&buffer) {
+        Ok(config) => config,
+        Err(e) => return write_text(req, 400, &e.to_string()),
+    };
+    if let Err(e) = config.validate() {
+        return write_text(req, 400, &e.to_string());
+    }
+
+    sensor_config::save(&config)?;
+    info!("sensors config saved: {:?}", config);
+
+    write_text(req, 200, "ok, restart to apply")
+}
+
+pub fn new_fan_handler(fan: Fan) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let status = fan.lock().unwrap().clone();
+        write_json(req, 200, &status)
+    }
+}
+
+/// The position of the encoder, null without one.
+pub fn new_encoder_handler(
+    counter: Option<Arc<Counter>>,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let position = counter.as_ref().map(|counter| counter.position());
+        write_json(req, 200, &position)
+    }
+}
+
+pub fn new_current_handler(
+    current: Current,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let status = current.lock().unwrap().clone();
+        write_json(req, 200, &status)
+    }
+}
+
+pub fn new_alarms_handler(
+    alarms: Alarms,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let report = alarms.lock().unwrap().report();
+        write_json(req, 200, &report)
+    }
+}
+
+/// Unlatches the alarm of `?kind=`, or all of them without it.
+pub fn new_alarms_ack_handler(
+    alarms: Alarms,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let kind = match query_param(req.uri(), "kind") {
+            Some(kind) => match serde_json::from_value::<Kind>(kind.into()) {
+                Ok(kind) => Some(kind),
+                Err(e) => return write_text(req, 400, &e.to_string()),
+            },
+            None => None,
+        };
+
+        let acknowledged = alarms.lock().unwrap().acknowledge(kind);
+
+        write_text(req, 200, &format!("ok, {} acknowledged", acknowledged))
+    }
+}
+
+pub fn new_protection_handler(
+    protection: Protection,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let config = protection.lock().unwrap().clone();
+        write_json(req, 200, &config)
+    }
+}
+
+/// Applied right away, and at boot.
+pub fn new_protection_put_handler(
+    protection: Protection,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |mut req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let buffer = match read_body(&mut req)? {
+            Ok(buffer) => buffer,
+            Err(invalid) => return write_invalid(req, &invalid),
+        };
+
+        let config: ProtectionConfig = match serde_json::from_slice(&buffer) {
+            Ok(config) => config,
+            Err(e) => return write_text(req, 400, &e.to_string()),
+        };
+        if let Err(e) = config.validate() {
+            return write_text(req, 400, &e.to_string());
+        }
+
+        protection::save(&config)?;
+        info!("protection saved: {:?}", config);
+        *protection.lock().unwrap() = config;
+
+        write_text(req, 200, "ok")
+    }
+}
+
+pub fn new_inputs_handler(
+    remote_values: RemoteValues,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        write_json(req, 200, &input::status(&remote_values))
+    }
+}
+
+/// Pushes a value for remote sources, e.g. `POST /input?name=cpu` with body `42.5`.
+pub fn new_input_handler(
+    remote_values: RemoteValues,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |mut req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let name = query_param(req.uri(), "name").unwrap_or("").to_string();
+        let ttl = match query_param(req.uri(), "ttl").map(str::parse::<u64>) {
+            Some(Ok(ttl)) => Some(ttl),
+            Some(Err(e)) => return write_text(req, 400, &e.to_string()),
+            None => None,
+        };
+
+        let buffer = match read_body(&mut req)? {
+            Ok(buffer) => buffer,
+            Err(invalid) => return write_invalid(req, &invalid),
+        };
+        let value = match serde_json::from_slice::<f32>(&buffer) {
+            Ok(value) => value,
+            Err(e) => return write_text(req, 400, &e.to_string()),
+        };
+
+        let push = Push { name, value, ttl };
+        if let Err(e) = push.validate() {
+            return write_text(req, 400, &e.to_string());
+        }
+        if let Err(e) = input::push(&remote_values, push) {
+            return write_text(req, 409, &e.to_string());
+        }
+
+        write_text(req, 200, "ok")
+    }
+}
+
+#[derive(Serialize)]
+struct ProfileList {
+    active: Option<String>,
+    profiles: Vec<profile::ProfileMeta>,
+}
+
+pub fn handle_profile_list(req: Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    let list = ProfileList {
+        active: profile::get_active_name()?,
+        profiles: profile::list()?,
+    };
+    write_json(req, 200, &list)
+}
+
+pub fn handle_profile_get(req: Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    let name = query_param(req.uri(), "name").unwrap_or("").to_string();
+    if let Err(e) = profile::validate_name(&name) {
+        return write_text(req, 400, &e.to_string());
+    }
+
+    match profile::get(&name)? {
+        Some(p) => write_json(req, 200, &p),
+        None => write_text(req, 404, "profile not found"),
+    }
+}
+
+pub fn handle_profile_put(mut req: Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    let mut p: profile::Profile = match read_config(&mut req) {
+        Ok(p) => p,
+        Err(invalid) => return write_invalid(req, &invalid),
+    };
+    if let Err(e) = profile::validate_name(&p.name) {
+        return write_invalid(req, &Invalid::unplayable("name", e));
+    }
+    if let Err(invalid) = prepare_config(&mut p.config) {
+        return write_invalid(req, &invalid.within("config"));
+    }
+
+    profile::put(&p)?;
+    info!("profile saved: {}", p.name);
+
+    write_text(req, 200, "ok")
+}
+
+pub fn handle_profile_delete(req: Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    let name = query_param(req.uri(), "name").unwrap_or("").to_string();
+    if let Err(e) = profile::validate_name(&name) {
+        return write_text(req, 400, &e.to_string());
+    }
+
+    if !profile::delete(&name)? {
+        return write_text(req, 404, "profile not found");
+    }
+    info!("profile deleted: {}", name);
+
+    write_text(req, 200, "ok")
+}
+
+/// Makes the named profile the active one and starts playing it.
+pub fn new_profile_activate_handler(
+    pwm_config: Arc<Mutex<PwmConfig>>,
+    transport: Arc<Mutex<Transport>>,
+    applied: Applied,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let name = query_param(req.uri(), "name").unwrap_or("").to_string();
+        if let Err(e) = profile::validate_name(&name) {
+            return write_text(req, 400, &e.to_string());
+        }
+
+        let p = match profile::get(&name)? {
+            Some(p) => p,
+            None => return write_text(req, 404, "profile not found"),
+        };
+
+        profile::set_active(&name)?;
+        info!("profile activated: {}", name);
+
+        *pwm_config.lock().unwrap() = p.config;
+        *transport.lock().unwrap() = Transport::Start;
+        applied.lock().unwrap().bump(Origin::Profile, Some(&name));
+
+        write_text(req, 200, "ok")
+    }
+}
+
+/// Requests the player to `Start` or `Stop`.
+pub fn new_transport_handler(
+    transport: Arc<Mutex<Transport>>,
+    request: Transport,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        *transport.lock().unwrap() = request;
+        write_text(req, 200, "ok")
+    }
+}
+
+/// Body of `GET /pwm`.
+#[derive(Serialize)]
+struct Playing {
+    config: PwmConfig,
+    #[serde(flatten)]
+    revision: Revision,
+    /// milliseconds since it was applied
+    age: u64,
+}
+
+/// The playing config and where it came from.
+pub fn new_pwm_handler(
+    pwm_config: Arc<Mutex<PwmConfig>>,
+    applied: Applied,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let config = pwm_config.lock().unwrap().clone();
+        let revision = applied.lock().unwrap().clone();
+        let playing = Playing {
+            config,
+            age: revision::uptime().saturating_sub(revision.applied_at),
+            revision,
+        };
+        write_json(req, 200, &playing)
+    }
+}
+
+/// The PID of the playing config, 404 if it has none.
+pub fn new_pid_handler(
+    pwm_config: Arc<Mutex<PwmConfig>>,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let pid = pwm_config.lock().unwrap().pid.clone();
+        match pid {
+            Some(pid) => write_json(req, 200, &pid),
+            None => write_text(req, 404, "no pid in the playing config"),
+        }
+    }
+}
+
+/// Gains to change, the others are kept.
+#[derive(Deserialize, Debug)]
+struct GainsPatch {
+    kp: Option<f32>,
+    ki: Option<f32>,
+    kd: Option<f32>,
+}
+
+/// Tunes the PID of the playing config without restarting it,
+/// e.g. `PUT /pid` with body `{"ki": 0.2}`, and saves it like `POST /pwm`.
+pub fn new_pid_gains_handler(
+    pwm_config: Arc<Mutex<PwmConfig>>,
+    applied: Applied,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |mut req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let buffer = match read_body(&mut req)? {
+            Ok(buffer) => buffer,
+            Err(invalid) => return write_invalid(req, &invalid),
+        };
+        let patch: GainsPatch = match serde_json::from_slice(&buffer) {
+            Ok(patch) => patch,
+            Err(e) => return write_text(req, 400, &e.to_string()),
+        };
+
+        // the player waits on the config, no response is written while it is locked
+        let current = pwm_config.lock().unwrap().pid.as_ref().map(|pid| pid.gains);
+        let current = match current {
+            Some(current) => current,
+            None => return write_text(req, 404, "no pid in the playing config"),
+        };
+
+        let gains = Gains {
+            kp: patch.kp.unwrap_or(current.kp),
+            ki: patch.ki.unwrap_or(current.ki),
+            kd: patch.kd.unwrap_or(current.kd),
+        };
+        if let Err(e) = gains.validate() {
+            return write_invalid(req, &Invalid::unplayable("gains", e));
+        }
+
+        let config = {
+            let mut config = pwm_config.lock().unwrap();
+            match config.pid.as_mut() {
+                Some(pid) => {
+                    pid.gains = gains;
+                    Some(config.clone())
+                }
+                None => None,
+            }
+        };
+        // replaced by a config without a pid meanwhile
+        let config = match config {
+            Some(config) => config,
+            None => return write_text(req, 404, "no pid in the playing config"),
+        };
+        info!("pid gains: {:?}", gains);
+        applied.lock().unwrap().bump(Origin::PidGains, None);
+
+        if let Err(e) = storage::save_config(&config) {
+            error!("config save error: {:?}", e);
+        }
+        // like an upload, the tuned config takes precedence over the active profile
+        if let Err(e) = profile::clear_active() {
+            error!("clear active profile error: {:?}", e);
+        }
+
+        write_text(req, 200, "ok")
+    }
+}
+
+/// Starts tuning the PID of the playing config with a relay, e.g. `{"rule": "tyreusLuyben"}`,
+/// an empty body takes the defaults.
+pub fn new_autotune_start_handler(
+    pwm_config: Arc<Mutex<PwmConfig>>,
+    autotune: Autotune,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |mut req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let buffer = match read_body(&mut req)? {
+            Ok(buffer) => buffer,
+            Err(invalid) => return write_invalid(req, &invalid),
+        };
+        let buffer = if buffer.is_empty() {
+            b"{}".to_vec()
+        } else {
+            buffer
+        };
+
+        let settings: autotune::Settings = match serde_json::from_slice(&buffer) {
+            Ok(settings) => settings,
+            Err(e) => return write_text(req, 400, &e.to_string()),
+        };
+        if let Err(e) = settings.validate() {
+            return write_text(req, 400, &e.to_string());
+        }
+
+        if pwm_config.lock().unwrap().pid.is_none() {
+            return write_text(req, 409, "no pid in the playing config");
+        }
+
+        info!("autotune requested: {:?}", settings);
+        let mut session = autotune.lock().unwrap();
+        session.request = Some(settings);
+        session.cancel = false;
+        session.status = None;
+        drop(session);
 
-unsafe impl Send for WrapperType {}
-unsafe impl Sync for WrapperType {}
+        write_text(req, 202, "ok, see GET /pid/autotune")
+    }
+}
 
-pub fn new_temperature_handler() -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
-    let mut ok = true;
-    let tsensor_handler: Arc<Mutex<WrapperType>> =
-        Arc::new(Mutex::new(WrapperType(std::ptr::null_mut())));
-    unsafe {
-        let mut tsensor_config: temperature_sensor_config_t = Default::default();
-        tsensor_config.range_min = 10;
-        tsensor_config.range_max = 50;
-        tsensor_config.clk_src = soc_module_clk_t_SOC_MOD_CLK_XTAL;
+pub fn new_autotune_status_handler(
+    autotune: Autotune,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let session = autotune.lock().unwrap();
+        let pending = session.request.is_some();
+        let status = session.status.clone();
+        drop(session);
 
-        let res =
-            temperature_sensor_install(&tsensor_config, &mut tsensor_handler.lock().unwrap().0);
-        if res != ESP_OK {
-            ok = false;
-            error!(
-                "Failed to install temperature sensor: {}",
-                esp32::esp_err_to_str(res)
-            );
+        match status {
+            Some(status) => write_json(req, 200, &status),
+            None if pending => write_text(req, 202, "starting"),
+            None => write_text(req, 404, "no autotune"),
         }
-        temperature_sensor_enable(tsensor_handler.lock().unwrap().0);
     }
+}
 
+pub fn new_autotune_cancel_handler(
+    autotune: Autotune,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
     move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
-        if !ok {
-            return new_error_handler("Failed to install temperature sensor")(req);
+        let mut session = autotune.lock().unwrap();
+        session.request = None;
+        session.cancel = true;
+        drop(session);
+
+        write_text(req, 200, "ok")
+    }
+}
+
+/// Starts a sweep of the fan on the output, e.g. `{"step": 5, "settle": 3000}`,
+/// an empty body takes the defaults.
+pub fn new_calibration_start_handler(
+    calibrate: Calibrate,
+    has_tach: bool,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |mut req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        if !has_tach {
+            return write_text(req, 409, "calibration needs a tachometer");
+        }
+
+        let buffer = match read_body(&mut req)? {
+            Ok(buffer) => buffer,
+            Err(invalid) => return write_invalid(req, &invalid),
+        };
+        let buffer = if buffer.is_empty() {
+            b"{}".to_vec()
+        } else {
+            buffer
+        };
+
+        let settings: calibration::Settings = match serde_json::from_slice(&buffer) {
+            Ok(settings) => settings,
+            Err(e) => return write_text(req, 400, &e.to_string()),
+        };
+        if let Err(e) = settings.validate() {
+            return write_text(req, 400, &e.to_string());
         }
 
-        let mut sensors = 0f32;
-        unsafe {
-            temperature_sensor_get_celsius(tsensor_handler.lock().unwrap().0, &mut sensors);
+        info!("calibration requested: {:?}", settings);
+        let mut session = calibrate.lock().unwrap();
+        session.request = Some(settings);
+        session.cancel = false;
+        session.status = None;
+        drop(session);
+
+        write_text(req, 202, "ok, see GET /calibration")
+    }
+}
+
+#[derive(Serialize)]
+struct CalibrationState {
+    status: Option<calibration::Status>,
+    calibration: Option<calibration::Calibration>,
+}
+
+pub fn new_calibration_handler(
+    calibrate: Calibrate,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let session = calibrate.lock().unwrap();
+        let state = CalibrationState {
+            status: session.status.clone(),
+            calibration: session.calibration.clone(),
         };
-        req.into_response(
-            200,
-            None,
-            &[("Content-type", "application/json; charset=UTF-8")],
-        )?
-        .write_all(sensors.to_string().as_bytes())?;
-        Ok(())
+        drop(session);
+
+        write_json(req, 200, &state)
+    }
+}
+
+/// Cancels a sweep and forgets the calibration, duties are played as they are again.
+pub fn new_calibration_delete_handler(
+    calibrate: Calibrate,
+) -> impl Fn(Request<&mut EspHttpConnection<'_>>) -> Result<()> {
+    move |req: Request<&mut EspHttpConnection<'_>>| -> Result<()> {
+        let mut session = calibrate.lock().unwrap();
+        session.request = None;
+        session.cancel = true;
+        session.calibration = None;
+        drop(session);
+
+        calibration::delete()?;
+        info!("calibration deleted");
+
+        write_text(req, 200, "ok")
     }
 }
@@ -0,0 +1,204 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::hal::{
+    delay::TickType,
+    gpio::AnyIOPin,
+    i2c::{I2cConfig, I2cDriver, I2C0},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{input::Source, thermo};
+
+const TIMEOUT: TickType = TickType::new_millis(100);
+
+const SHT3X_MEASURE_HIGH: [u8; 2] = [0x24, 0x00];
+const SHT3X_MEASUREMENT_TIME: Duration = Duration::from_millis(16);
+
+const BMP280_CHIP_ID: u8 = 0xD0;
+const BMP280_CALIBRATION: u8 = 0x88;
+const BMP280_CTRL_MEAS: u8 = 0xF4;
+const BMP280_TEMP: u8 = 0xFA;
+/// temperature oversampling x1, no pressure, forced mode
+const BMP280_FORCED_TEMPERATURE: u8 = 0x21;
+const BMP280_MEASUREMENT_TIME: Duration = Duration::from_millis(10);
+/// BMP280, and the BME280 which measures temperature the same way
+const BMP280_IDS: [u8; 2] = [0x58, 0x60];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BusConfig {
+    pub sda: i32,
+    pub scl: i32,
+    /// in hertz
+    #[serde(default = "default_frequency")]
+    pub frequency: u32,
+}
+
+fn default_frequency() -> u32 {
+    100_000
+}
+
+/// Every I2C sensor shares I2C0.
+pub type Bus = Arc<Mutex<I2cDriver<'static>>>;
+
+pub fn new_bus(config: &BusConfig) -> Result<Bus> {
+    // I2C0 and the pins are only taken here, once at boot, for the sensors of the config,
+    // the pins are checked by `pins::check_all`
+    let driver = unsafe {
+        I2cDriver::new(
+            I2C0::new(),
+            AnyIOPin::new(config.sda),
+            AnyIOPin::new(config.scl),
+            &I2cConfig::new().baudrate(config.frequency.Hz()),
+        )?
+    };
+    Ok(Arc::new(Mutex::new(driver)))
+}
+
+/// A SHT3x in single shot mode, at 0x44 or 0x45.
+pub struct Sht3x {
+    bus: Bus,
+    address: u8,
+    /// of the measurement in progress
+    measuring_since: Option<Instant>,
+}
+
+pub fn new_sht3x(bus: Bus, address: u8) -> Result<Sht3x> {
+    let mut sensor = Sht3x {
+        bus,
+        address,
+        measuring_since: None,
+    };
+    sensor.read()?;
+    Ok(sensor)
+}
+
+impl Sht3x {
+    /// Starts a measurement, read it once `SHT3X_MEASUREMENT_TIME` passed.
+    pub fn measure(&mut self) -> Result<Duration> {
+        self.bus
+            .lock()
+            .unwrap()
+            .write(self.address, &SHT3X_MEASURE_HIGH, TIMEOUT.ticks())?;
+        self.measuring_since = Some(Instant::now());
+        Ok(SHT3X_MEASUREMENT_TIME)
+    }
+
+    /// In celsius, blocks for the rest of a measurement, or for a whole one if none was started.
+    pub fn read(&mut self) -> Result<f32> {
+        let since = match self.measuring_since.take() {
+            Some(since) => since,
+            None => {
+                self.measure()?;
+                self.measuring_since.take().unwrap()
+            }
+        };
+        thread::sleep(SHT3X_MEASUREMENT_TIME.saturating_sub(since.elapsed()));
+
+        let mut data = [0u8; 6];
+        self.bus
+            .lock()
+            .unwrap()
+            .read(self.address, &mut data, TIMEOUT.ticks())?;
+
+        thermo::sht3x_measurement(&data).map_err(|e| anyhow!("SHT3x: {}", e))
+    }
+}
+
+impl Source for Sht3x {
+    fn read(&mut self) -> Result<f32> {
+        Sht3x::read(self)
+    }
+
+    fn start(&mut self) -> Result<Duration> {
+        self.measure()
+    }
+}
+
+/// A BMP280 (or BME280) in forced mode, at 0x76 or 0x77.
+pub struct Bmp280 {
+    bus: Bus,
+    address: u8,
+    calibration: thermo::Bmp280Calibration,
+    /// of the measurement in progress
+    measuring_since: Option<Instant>,
+}
+
+pub fn new_bmp280(bus: Bus, address: u8) -> Result<Bmp280> {
+    let mut id = [0u8];
+    let mut registers = [0u8; 6];
+    {
+        let mut driver = bus.lock().unwrap();
+        driver.write_read(address, &[BMP280_CHIP_ID], &mut id, TIMEOUT.ticks())?;
+        if !BMP280_IDS.contains(&id[0]) {
+            return Err(anyhow!(
+                "0x{:02x} is no BMP280, chip id 0x{:02x}",
+                address,
+                id[0]
+            ));
+        }
+        driver.write_read(
+            address,
+            &[BMP280_CALIBRATION],
+            &mut registers,
+            TIMEOUT.ticks(),
+        )?;
+    }
+
+    Ok(Bmp280 {
+        bus,
+        address,
+        calibration: thermo::Bmp280Calibration::from_registers(&registers),
+        measuring_since: None,
+    })
+}
+
+impl Bmp280 {
+    /// Starts a measurement, read it once `BMP280_MEASUREMENT_TIME` passed.
+    pub fn measure(&mut self) -> Result<Duration> {
+        self.bus.lock().unwrap().write(
+            self.address,
+            &[BMP280_CTRL_MEAS, BMP280_FORCED_TEMPERATURE],
+            TIMEOUT.ticks(),
+        )?;
+        self.measuring_since = Some(Instant::now());
+        Ok(BMP280_MEASUREMENT_TIME)
+    }
+
+    /// In celsius, blocks for the rest of a measurement, or for a whole one if none was started.
+    pub fn read(&mut self) -> Result<f32> {
+        let since = match self.measuring_since.take() {
+            Some(since) => since,
+            None => {
+                self.measure()?;
+                self.measuring_since.take().unwrap()
+            }
+        };
+        thread::sleep(BMP280_MEASUREMENT_TIME.saturating_sub(since.elapsed()));
+
+        let mut registers = [0u8; 3];
+        self.bus.lock().unwrap().write_read(
+            self.address,
+            &[BMP280_TEMP],
+            &mut registers,
+            TIMEOUT.ticks(),
+        )?;
+
+        Ok(self.calibration.celsius(thermo::bmp280_adc_t(&registers)))
+    }
+}
+
+impl Source for Bmp280 {
+    fn read(&mut self) -> Result<f32> {
+        Bmp280::read(self)
+    }
+
+    fn start(&mut self) -> Result<Duration> {
+        self.measure()
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// Where the x of a lookup comes from.
+pub trait Source: Send {
+    fn read(&mut self) -> Result<f32>;
+
+    /// Starts a measurement `read` takes once it is ready, how long that takes, e.g. a conversion.
+    /// The sensor service samples other sensors meanwhile instead of blocking in `read`.
+    fn start(&mut self) -> Result<Duration> {
+        Ok(Duration::ZERO)
+    }
+
+    /// When the value of the last `read` stops being valid, e.g. a remote value with a ttl.
+    /// A sensor reading it is stale from then on, see `sensor::Reading::fresh`.
+    fn expires_at(&self) -> Option<Instant> {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceConfig {
+    /// milliseconds since the player started, wrapped by `period` if it is not 0
+    Time {
+        #[serde(default)]
+        period: u64,
+    },
+    /// millivolts of an ADC1 pin, e.g. a potentiometer
+    Adc { gpio: i32 },
+    /// celsius of the chip, same as the sensor named `chip`
+    #[default]
+    Temperature,
+    /// a sensor of the sensor service by name, see `GET /sensors`
+    Sensor { name: String },
+    /// a value pushed over the network, see `POST /input`, missing once it expired
+    Remote { name: String },
+}
+
+impl SourceConfig {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            SourceConfig::Remote { name } if name.is_empty() => {
+                Err(anyhow!("remote source needs a name"))
+            }
+            SourceConfig::Sensor { name } if name.is_empty() => {
+                Err(anyhow!("sensor source needs a name"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct Elapsed {
+    started_at: Instant,
+    period: u64,
+}
+
+impl Elapsed {
+    pub fn new(period: u64) -> Self {
+        Elapsed {
+            started_at: Instant::now(),
+            period,
+        }
+    }
+}
+
+impl Source for Elapsed {
+    fn read(&mut self) -> Result<f32> {
+        let elapsed = self.started_at.elapsed().as_millis() as u64;
+        if self.period == 0 {
+            return Ok(elapsed as f32);
+        }
+        Ok((elapsed % self.period) as f32)
+    }
+}
+
+/// A value pushed over the network.
+#[derive(Debug, Clone)]
+pub struct RemoteValue {
+    pub value: f32,
+    /// milliseconds it is valid for, forever without it
+    pub ttl: Option<u64>,
+    pub pushed_at: Instant,
+}
+
+impl RemoteValue {
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.ttl
+            .map(|ttl| self.pushed_at + Duration::from_millis(ttl))
+    }
+
+    /// The value, unless it expired.
+    pub fn fresh(&self) -> Option<f32> {
+        match self.expires_at() {
+            Some(at) if Instant::now() > at => None,
+            _ => Some(self.value),
+        }
+    }
+}
+
+/// Names kept at once, a push of a new name is rejected beyond them.
+pub const MAX_REMOTE_VALUES: usize = 32;
+/// in bytes
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Latest value of each name pushed over the network.
+pub type RemoteValues = Arc<Mutex<HashMap<String, RemoteValue>>>;
+
+/// A push of `POST /input` or of a UDP datagram.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Push {
+    pub name: String,
+    pub value: f32,
+    /// in milliseconds
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+impl Push {
+    pub fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            return Err(anyhow!("name is required"));
+        }
+        if self.name.len() > MAX_NAME_LEN {
+            return Err(anyhow!("name is longer than {} bytes", MAX_NAME_LEN));
+        }
+        Ok(())
+    }
+}
+
+/// Expired values are dropped first, so only names pushed recently count against the cap.
+pub fn push(values: &RemoteValues, push: Push) -> Result<()> {
+    let mut values = values.lock().unwrap();
+    values.retain(|_, value| value.fresh().is_some());
+    if values.len() >= MAX_REMOTE_VALUES && !values.contains_key(&push.name) {
+        return Err(anyhow!("more than {} remote values", MAX_REMOTE_VALUES));
+    }
+
+    values.insert(
+        push.name,
+        RemoteValue {
+            value: push.value,
+            ttl: push.ttl,
+            pushed_at: Instant::now(),
+        },
+    );
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+pub struct RemoteStatus {
+    pub value: f32,
+    pub ttl: Option<u64>,
+    /// milliseconds since the push
+    pub age: u64,
+    pub expired: bool,
+}
+
+pub fn status(values: &RemoteValues) -> HashMap<String, RemoteStatus> {
+    values
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, value)| {
+            let status = RemoteStatus {
+                value: value.value,
+                ttl: value.ttl,
+                age: value.pushed_at.elapsed().as_millis() as u64,
+                expired: value.fresh().is_none(),
+            };
+            (name.clone(), status)
+        })
+        .collect()
+}
+
+pub struct Remote {
+    values: RemoteValues,
+    name: String,
+    /// of the last value read
+    expires_at: Option<Instant>,
+}
+
+impl Remote {
+    pub fn new(values: RemoteValues, name: &str) -> Self {
+        Remote {
+            values,
+            name: name.to_string(),
+            expires_at: None,
+        }
+    }
+}
+
+impl Source for Remote {
+    fn read(&mut self) -> Result<f32> {
+        let values = self.values.lock().unwrap();
+        let value = values
+            .get(&self.name)
+            .ok_or_else(|| anyhow!("no remote value of {}", self.name))?;
+        let fresh = value
+            .fresh()
+            .ok_or_else(|| anyhow!("remote value of {} expired", self.name))?;
+        self.expires_at = value.expires_at();
+        Ok(fresh)
+    }
+
+    fn expires_at(&self) -> Option<Instant> {
+        self.expires_at
+    }
+}
+
+/// Sources that play back readings, to drive the player logic on the host.
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+
+    /// An error where a reading is None, and once all were read.
+    pub struct Scripted {
+        readings: std::vec::IntoIter<Option<f32>>,
+    }
+
+    impl Scripted {
+        pub fn new(readings: Vec<Option<f32>>) -> Self {
+            Scripted {
+                readings: readings.into_iter(),
+            }
+        }
+    }
+
+    impl Source for Scripted {
+        fn read(&mut self) -> Result<f32> {
+            match self.readings.next() {
+                Some(Some(x)) => Ok(x),
+                Some(None) => Err(anyhow!("scripted failure")),
+                None => Err(anyhow!("no more readings")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(name: &str, value: f32, ttl: Option<u64>) -> Push {
+        Push {
+            name: name.to_string(),
+            value,
+            ttl,
+        }
+    }
+
+    #[test]
+    fn validate() {
+        assert!(named("cpu", 1.0, None).validate().is_ok());
+        assert!(named("", 1.0, None).validate().is_err());
+        assert!(named(&"x".repeat(MAX_NAME_LEN), 1.0, None)
+            .validate()
+            .is_ok());
+        assert!(named(&"x".repeat(MAX_NAME_LEN + 1), 1.0, None)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn replaces_a_name() {
+        let values: RemoteValues = Default::default();
+        push(&values, named("cpu", 1.0, None)).unwrap();
+        push(&values, named("cpu", 2.0, None)).unwrap();
+        assert_eq!(values.lock().unwrap().len(), 1);
+        assert_eq!(Remote::new(values, "cpu").read().unwrap(), 2.0);
+    }
+
+    #[test]
+    fn caps_the_names() {
+        let values: RemoteValues = Default::default();
+        for i in 0..MAX_REMOTE_VALUES {
+            push(&values, named(&format!("input{}", i), 1.0, None)).unwrap();
+        }
+        assert!(push(&values, named("one more", 1.0, None)).is_err());
+        // a known name is still taken
+        push(&values, named("input0", 2.0, None)).unwrap();
+        assert_eq!(values.lock().unwrap().len(), MAX_REMOTE_VALUES);
+    }
+
+    #[test]
+    fn expiry_of_the_value_read() {
+        let values: RemoteValues = Default::default();
+        let mut remote = Remote::new(Arc::clone(&values), "cpu");
+
+        push(&values, named("cpu", 1.0, Some(60_000))).unwrap();
+        remote.read().unwrap();
+        let pushed_at = values.lock().unwrap()["cpu"].pushed_at;
+        assert_eq!(
+            remote.expires_at(),
+            Some(pushed_at + Duration::from_secs(60))
+        );
+
+        push(&values, named("cpu", 2.0, None)).unwrap();
+        remote.read().unwrap();
+        assert_eq!(remote.expires_at(), None);
+    }
+
+    #[test]
+    fn drops_expired_on_push() {
+        let values: RemoteValues = Default::default();
+        for i in 0..MAX_REMOTE_VALUES {
+            push(&values, named(&format!("input{}", i), 1.0, Some(0))).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(Remote::new(Arc::clone(&values), "input0").read().is_err());
+
+        push(&values, named("fresh", 1.0, Some(60_000))).unwrap();
+        let values = values.lock().unwrap();
+        assert_eq!(values.len(), 1);
+        assert!(values.contains_key("fresh"));
+    }
+}
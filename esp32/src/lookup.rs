@@ -0,0 +1,235 @@
+use std::time::Instant;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::input::{Source, SourceConfig};
+
+/// Plays steps as a function of a reading instead of time,
+/// e.g. a fan curve over temperature.
+/// Steps are spread evenly from `min` to `max` and interpolated in between.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lookup {
+    /// the chip temperature by default
+    #[serde(default)]
+    pub source: SourceConfig,
+    /// reading of the first step
+    pub min: f32,
+    /// reading of the last step
+    pub max: f32,
+    /// in milliseconds
+    #[serde(rename = "sampleInterval", default = "default_sample_interval")]
+    pub sample_interval: u64,
+    /// how far a reading has to fall before the duty follows it down
+    #[serde(default)]
+    pub hysteresis: f32,
+    /// how far the mapped duty has to move before the played duty follows it
+    #[serde(default)]
+    pub deadband: f32,
+}
+
+fn default_sample_interval() -> u64 {
+    1000
+}
+
+/// Readings outside `min..=max` hold the first or the last step.
+pub fn interpolate(steps: &[i32], min: f32, max: f32, x: f32) -> f32 {
+    let last = match steps.len() {
+        0 => return 0.0,
+        len => len - 1,
+    };
+    if last == 0 || max <= min {
+        return steps[0] as f32;
+    }
+
+    let position = ((x - min) / (max - min)).clamp(0.0, 1.0) * last as f32;
+    let index = position.floor() as usize;
+    if index >= last {
+        return steps[last] as f32;
+    }
+
+    let from = steps[index] as f32;
+    let to = steps[index + 1] as f32;
+    from + (to - from) * (position - index as f32)
+}
+
+/// Rising readings are followed right away,
+/// falling ones only after dropping more than `width` below the followed value,
+/// so a fan does not hunt around a step of the curve.
+#[derive(Debug, Default)]
+pub struct Hysteresis {
+    value: Option<f32>,
+}
+
+impl Hysteresis {
+    pub fn update(&mut self, x: f32, width: f32) -> f32 {
+        let value = match self.value {
+            Some(value) if x <= value && x >= value - width => value,
+            _ => x,
+        };
+        self.value = Some(value);
+        value
+    }
+}
+
+/// The played duty only follows a mapped one that moved more than `width` away,
+/// except to one of the `ends` of the curve, so the first and last step are always reached.
+#[derive(Debug, Default)]
+pub struct Deadband {
+    value: Option<f32>,
+}
+
+impl Deadband {
+    pub fn update(&mut self, duty: f32, width: f32, ends: [f32; 2]) -> f32 {
+        let value = match self.value {
+            Some(value) if (duty - value).abs() <= width && !ends.contains(&duty) => value,
+            _ => duty,
+        };
+        self.value = Some(value);
+        value
+    }
+
+    pub fn reset(&mut self) {
+        self.value = None;
+    }
+}
+
+/// Takes a reading every `sample_interval` of a lookup, with hysteresis applied.
+#[derive(Debug, Default)]
+pub struct Sampler {
+    sampled_at: Option<Instant>,
+    reading: Option<f32>,
+    hysteresis: Hysteresis,
+}
+
+impl Sampler {
+    /// None until the first successful reading, or after a failed one.
+    pub fn sample(&mut self, lookup: &Lookup, source: &mut dyn Source) -> Option<f32> {
+        let due = match self.sampled_at {
+            Some(at) => at.elapsed().as_millis() as u64 >= lookup.sample_interval,
+            None => true,
+        };
+        if !due {
+            return self.reading;
+        }
+
+        self.sampled_at = Some(Instant::now());
+        self.reading = match source.read() {
+            Ok(x) => Some(self.hysteresis.update(x, lookup.hysteresis)),
+            Err(e) => {
+                warn!("lookup reading error: {:?}", e);
+                None
+            }
+        };
+        self.reading
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::mock::Scripted;
+
+    fn lookup(sample_interval: u64, hysteresis: f32) -> Lookup {
+        Lookup {
+            source: SourceConfig::Sensor {
+                name: "case".to_string(),
+            },
+            min: 30.0,
+            max: 50.0,
+            sample_interval,
+            hysteresis,
+            deadband: 0.0,
+        }
+    }
+
+    #[test]
+    fn interpolates_over_readings() {
+        let steps = [0, 100, 200];
+        assert_eq!(interpolate(&steps, 30.0, 50.0, 20.0), 0.0);
+        assert_eq!(interpolate(&steps, 30.0, 50.0, 35.0), 50.0);
+        assert_eq!(interpolate(&steps, 30.0, 50.0, 45.0), 150.0);
+        assert_eq!(interpolate(&steps, 30.0, 50.0, 60.0), 200.0);
+        assert_eq!(interpolate(&[], 30.0, 50.0, 40.0), 0.0);
+        assert_eq!(interpolate(&[7], 30.0, 50.0, 40.0), 7.0);
+    }
+
+    #[test]
+    fn drives_a_lookup_from_a_source() {
+        let lookup = lookup(0, 2.0);
+        let steps = [0, 100, 200];
+        let mut source = Scripted::new(vec![
+            Some(30.0),
+            Some(45.0),
+            // within the hysteresis, held at 45
+            Some(44.0),
+            Some(42.0),
+            None,
+            Some(60.0),
+        ]);
+        let mut sampler = Sampler::default();
+
+        let duties: Vec<Option<f32>> = (0..6)
+            .map(|_| {
+                sampler
+                    .sample(&lookup, &mut source)
+                    .map(|x| interpolate(&steps, lookup.min, lookup.max, x))
+            })
+            .collect();
+        assert_eq!(
+            duties,
+            [
+                Some(0.0),
+                Some(150.0),
+                Some(150.0),
+                Some(120.0),
+                None,
+                Some(200.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn samples_once_an_interval() {
+        let lookup = lookup(60_000, 0.0);
+        let mut source = Scripted::new(vec![Some(40.0)]);
+        let mut sampler = Sampler::default();
+
+        assert_eq!(sampler.sample(&lookup, &mut source), Some(40.0));
+        // not due, the source would fail if it was read again
+        assert_eq!(sampler.sample(&lookup, &mut source), Some(40.0));
+    }
+
+    #[test]
+    fn deadband_holds_small_moves() {
+        let ends = [0.0, 200.0];
+        let mut deadband = Deadband::default();
+        let duties: Vec<f32> = [100.0, 104.0, 96.0, 106.0, 103.0, 110.0, 111.9]
+            .iter()
+            .map(|duty| deadband.update(*duty, 5.0, ends))
+            .collect();
+        assert_eq!(duties, [100.0, 100.0, 100.0, 106.0, 106.0, 106.0, 111.9]);
+    }
+
+    #[test]
+    fn deadband_reaches_the_ends() {
+        let ends = [0.0, 200.0];
+        let mut deadband = Deadband::default();
+        assert_eq!(deadband.update(3.0, 5.0, ends), 3.0);
+        assert_eq!(deadband.update(0.0, 5.0, ends), 0.0);
+        assert_eq!(deadband.update(197.0, 5.0, ends), 197.0);
+        assert_eq!(deadband.update(200.0, 5.0, ends), 200.0);
+        assert_eq!(deadband.update(198.0, 5.0, ends), 200.0);
+    }
+
+    #[test]
+    fn deadband_of_zero_follows() {
+        let mut deadband = Deadband::default();
+        assert_eq!(deadband.update(10.0, 0.0, [0.0, 200.0]), 10.0);
+        assert_eq!(deadband.update(10.5, 0.0, [0.0, 200.0]), 10.5);
+
+        deadband.update(50.0, 100.0, [0.0, 200.0]);
+        deadband.reset();
+        assert_eq!(deadband.update(60.0, 100.0, [0.0, 200.0]), 60.0);
+    }
+}
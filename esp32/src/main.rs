@@ -12,10 +12,42 @@ use esp_idf_svc::{
 };
 use log::{error, info};
 
+mod adc;
+mod alarm;
+mod angle;
+mod autotune;
+mod binary;
+mod calibration;
+mod compose;
+mod current;
+mod curve;
+mod ds18b20;
+mod encoder;
 mod esp32;
+mod fan;
+mod filter;
 mod http_handler;
+mod i2c_sensor;
+mod input;
+mod lookup;
+mod ntc;
+mod pid;
+mod pins;
+mod profile;
+mod protection;
 mod pwm;
+mod pwm_loop;
+mod revision;
+mod sensor;
+mod sensor_config;
+mod steps;
 mod storage;
+mod tach;
+mod temperature;
+mod thermo;
+mod udp_input;
+mod validation;
+mod waveform;
 mod wifi;
 
 #[toml_cfg::toml_config]
@@ -26,6 +58,9 @@ pub struct Config {
     wifi_ssid: &'static str,
     #[default("")]
     wifi_psk: &'static str,
+    /// Port of the UDP listener for remote values, 0 does not listen.
+    #[default(4210)]
+    input_port: u16,
 }
 
 fn main() -> Result<()> {
@@ -36,25 +71,103 @@ fn main() -> Result<()> {
     let sysloop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
 
-    let steps: Arc<Mutex<Vec<i32>>> = Arc::new(Mutex::new(vec![]));
-    let interval: Arc<Mutex<u64>> = Arc::new(Mutex::new(100));
+    let pwm_config: Arc<Mutex<storage::PwmConfig>> = Arc::new(Mutex::new(Default::default()));
+    let transport: Arc<Mutex<pwm_loop::Transport>> =
+        Arc::new(Mutex::new(pwm_loop::Transport::Start));
+    let applied: revision::Applied = Default::default();
 
     // setup spiffs
     storage::new()?;
 
-    // read saved config
-    if let Some(pwm_config) = storage::get_config()? {
-        info!("read pwm config: {:?}", pwm_config);
-        let mut steps = steps.lock().unwrap();
-        *steps = pwm_config.steps.clone();
-        let mut interval = interval.lock().unwrap();
-        *interval = pwm_config.interval;
+    let sensors_config = sensor_config::get()?;
+    info!("read sensors config: {:?}", sensors_config);
+    let mut sensor_definitions = vec![sensor::Definition {
+        name: pwm_loop::CHIP_SENSOR.to_string(),
+        interval: 1000,
+        filters: sensors_config.chip_filters.clone(),
+        open: Box::new(|| Ok(Box::new(temperature::new()?) as Box<dyn input::Source>)),
+    }];
+    let readings: sensor::Readings = Default::default();
+    let remote_values: input::RemoteValues = Default::default();
+    let registry: sensor::Registry = Default::default();
+    let inputs = pwm_loop::Inputs {
+        readings: Arc::clone(&readings),
+        remote_values: Arc::clone(&remote_values),
+        registry: registry.clone(),
+    };
+    sensor_definitions.extend(sensor_config::definitions(&sensors_config, &inputs));
+    let _sensor_handler = sensor::new(Arc::clone(&readings), registry, sensor_definitions);
+    let autotune: autotune::Autotune = Default::default();
+    let fan: fan::Fan = Default::default();
+    let calibrate: calibration::Calibrate = Default::default();
+    let alarms: alarm::Alarms = Default::default();
+    let current: current::Current = Default::default();
+
+    // read active profile, fallback to the last uploaded config
+    let active = match profile::get_active() {
+        Result::Ok(active) => active,
+        Err(e) => {
+            error!("read active profile error: {:?}", e);
+            None
+        }
+    };
+    if let Some(profile) = active {
+        info!("read active profile: {:?}", profile);
+        *pwm_config.lock().unwrap() = profile.config;
+        applied
+            .lock()
+            .unwrap()
+            .bump(revision::Origin::BootProfile, Some(&profile.name));
+    } else if let Some(config) = storage::get_config()? {
+        info!("read pwm config: {:?}", config);
+        *pwm_config.lock().unwrap() = config;
+        applied
+            .lock()
+            .unwrap()
+            .bump(revision::Origin::BootFile, None);
     } else {
         info!("no pwm config found");
     }
 
+    match calibration::get() {
+        Result::Ok(calibration) => {
+            info!("read calibration: {:?}", calibration);
+            calibrate.lock().unwrap().calibration = calibration;
+        }
+        Err(e) => error!("read calibration error: {:?}", e),
+    }
+
+    let protection_config = protection::get()?;
+    info!("read protection: {:?}", protection_config);
+    let protection: protection::Protection = Arc::new(Mutex::new(protection_config));
+
+    let tach = match &sensors_config.tach {
+        Some(tach_config) => match tach::new(tach_config) {
+            Result::Ok(tach) => Some(tach),
+            Err(e) => {
+                error!("tach error: {:?}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    let has_tach = tach.is_some();
+
+    let encoder = match &sensors_config.encoder {
+        Some(encoder_config) => match encoder::new(encoder_config) {
+            Result::Ok(encoder) => Some(encoder),
+            Err(e) => {
+                error!("encoder error: {:?}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let counter = encoder.as_ref().map(|encoder| encoder.counter());
+
     #[cfg(feature = "esp-c3-32s")]
-    let pinner = main_loop::Pinner {
+    let pinner = pwm_loop::Pinner {
         direction: PinDriver::output(peripherals.pins.gpio5)?, // blue led
         led: pwm::new_20khz(
             peripherals.ledc.timer0,
@@ -66,10 +179,12 @@ fn main() -> Result<()> {
             peripherals.ledc.channel1,
             peripherals.pins.gpio3, // red led
         )?,
+        tach,
+        encoder,
     };
 
     #[cfg(feature = "esp32-c3-supermini")]
-    let pinner = main_loop::Pinner {
+    let pinner = pwm_loop::Pinner {
         direction: PinDriver::output(peripherals.pins.gpio0)?,
         led: pwm::new_20khz(
             peripherals.ledc.timer0,
@@ -81,9 +196,24 @@ fn main() -> Result<()> {
             peripherals.ledc.channel1,
             peripherals.pins.gpio3,
         )?,
+        tach,
+        encoder,
     };
 
-    let pwm_loop_handler = main_loop::new(pinner, Arc::clone(&interval), Arc::clone(&steps));
+    let pwm_loop_handler = pwm_loop::new(
+        pinner,
+        Arc::clone(&pwm_config),
+        Arc::clone(&transport),
+        inputs,
+        pwm_loop::Shared {
+            autotune: Arc::clone(&autotune),
+            fan: Arc::clone(&fan),
+            calibrate: Arc::clone(&calibrate),
+            protection: Arc::clone(&protection),
+            alarms: Arc::clone(&alarms),
+            current: Arc::clone(&current),
+        },
+    );
 
     let w = wifi::new(
         peripherals.modem,
@@ -95,52 +225,177 @@ fn main() -> Result<()> {
     )?;
     wifi::guard(w, Duration::from_secs(10));
 
+    let _udp_input_handler = if CONFIG.input_port > 0 {
+        match udp_input::new(CONFIG.input_port, Arc::clone(&remote_values)) {
+            Result::Ok(handler) => Some(handler),
+            Err(e) => {
+                error!("udp input error: {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
     // http server
-    let mut server = server::EspHttpServer::new(&server::Configuration::default())?;
+    let mut server = server::EspHttpServer::new(&server::Configuration {
+        // deserializing nested compositions takes more than the default 6k
+        stack_size: 10240,
+        ..Default::default()
+    })?;
     server.fn_handler("/", Method::Get, http_handler::handle_index)?;
     server.fn_handler("/favicon.ico", Method::Get, http_handler::handle_favicon)?;
     server.fn_handler(
         "/sensors",
         Method::Get,
-        http_handler::new_temperature_handler(),
+        http_handler::new_sensors_handler(Arc::clone(&readings)),
+    )?;
+    server.fn_handler(
+        "/sensors/config",
+        Method::Get,
+        http_handler::handle_sensors_config_get,
+    )?;
+    server.fn_handler(
+        "/sensors/config",
+        Method::Put,
+        http_handler::handle_sensors_config_put,
     )?;
 
-    let cloned_steps = Arc::clone(&steps);
-    let cloned_interval = Arc::clone(&interval);
-    server.fn_handler("/pwm", Method::Post, move |mut req| -> Result<()> {
-        let size = req
-            .header("Content-Length")
-            .unwrap_or("0")
-            .parse::<usize>()
-            .unwrap_or(0);
-
-        let mut buffer = if size > 0 {
-            Vec::with_capacity(size)
-        } else {
-            Vec::new()
-        };
+    server.fn_handler(
+        "/fan",
+        Method::Get,
+        http_handler::new_fan_handler(Arc::clone(&fan)),
+    )?;
+    server.fn_handler(
+        "/encoder",
+        Method::Get,
+        http_handler::new_encoder_handler(counter),
+    )?;
+    server.fn_handler(
+        "/current",
+        Method::Get,
+        http_handler::new_current_handler(Arc::clone(&current)),
+    )?;
+    server.fn_handler(
+        "/alarms",
+        Method::Get,
+        http_handler::new_alarms_handler(Arc::clone(&alarms)),
+    )?;
+    server.fn_handler(
+        "/alarms/ack",
+        Method::Post,
+        http_handler::new_alarms_ack_handler(Arc::clone(&alarms)),
+    )?;
+    server.fn_handler(
+        "/protection",
+        Method::Get,
+        http_handler::new_protection_handler(Arc::clone(&protection)),
+    )?;
+    server.fn_handler(
+        "/protection",
+        Method::Put,
+        http_handler::new_protection_put_handler(Arc::clone(&protection)),
+    )?;
+    server.fn_handler(
+        "/calibration",
+        Method::Post,
+        http_handler::new_calibration_start_handler(Arc::clone(&calibrate), has_tach),
+    )?;
+    server.fn_handler(
+        "/calibration",
+        Method::Get,
+        http_handler::new_calibration_handler(Arc::clone(&calibrate)),
+    )?;
+    server.fn_handler(
+        "/calibration",
+        Method::Delete,
+        http_handler::new_calibration_delete_handler(Arc::clone(&calibrate)),
+    )?;
+    server.fn_handler(
+        "/inputs",
+        Method::Get,
+        http_handler::new_inputs_handler(Arc::clone(&remote_values)),
+    )?;
+    server.fn_handler(
+        "/input",
+        Method::Post,
+        http_handler::new_input_handler(Arc::clone(&remote_values)),
+    )?;
+    server.fn_handler("/profiles", Method::Get, http_handler::handle_profile_list)?;
+    server.fn_handler("/profile", Method::Get, http_handler::handle_profile_get)?;
+    server.fn_handler("/profile", Method::Put, http_handler::handle_profile_put)?;
+    server.fn_handler(
+        "/profile",
+        Method::Delete,
+        http_handler::handle_profile_delete,
+    )?;
+    server.fn_handler(
+        "/profile/active",
+        Method::Post,
+        http_handler::new_profile_activate_handler(
+            Arc::clone(&pwm_config),
+            Arc::clone(&transport),
+            Arc::clone(&applied),
+        ),
+    )?;
+    server.fn_handler(
+        "/pwm/start",
+        Method::Post,
+        http_handler::new_transport_handler(Arc::clone(&transport), pwm_loop::Transport::Start),
+    )?;
+    server.fn_handler(
+        "/pwm/stop",
+        Method::Post,
+        http_handler::new_transport_handler(Arc::clone(&transport), pwm_loop::Transport::Stop),
+    )?;
+
+    server.fn_handler(
+        "/pid",
+        Method::Get,
+        http_handler::new_pid_handler(Arc::clone(&pwm_config)),
+    )?;
+    server.fn_handler(
+        "/pid",
+        Method::Put,
+        http_handler::new_pid_gains_handler(Arc::clone(&pwm_config), Arc::clone(&applied)),
+    )?;
+    server.fn_handler(
+        "/pid/autotune",
+        Method::Post,
+        http_handler::new_autotune_start_handler(Arc::clone(&pwm_config), Arc::clone(&autotune)),
+    )?;
+    server.fn_handler(
+        "/pid/autotune",
+        Method::Get,
+        http_handler::new_autotune_status_handler(Arc::clone(&autotune)),
+    )?;
+    server.fn_handler(
+        "/pid/autotune",
+        Method::Delete,
+        http_handler::new_autotune_cancel_handler(Arc::clone(&autotune)),
+    )?;
 
-        let mut temp_buffer = [0u8; 1024];
+    server.fn_handler(
+        "/pwm",
+        Method::Get,
+        http_handler::new_pwm_handler(Arc::clone(&pwm_config), Arc::clone(&applied)),
+    )?;
 
-        loop {
-            let bytes_read = req.read(&mut temp_buffer)?;
-            if bytes_read == 0 {
-                break;
-            }
-            buffer.extend_from_slice(&temp_buffer[..bytes_read]);
+    let cloned_pwm_config = Arc::clone(&pwm_config);
+    let cloned_transport = Arc::clone(&transport);
+    let cloned_applied = Arc::clone(&applied);
+    server.fn_handler("/pwm", Method::Post, move |mut req| -> Result<()> {
+        let mut config = match http_handler::read_pwm_config(&mut req) {
+            Result::Ok(config) => config,
+            Err(invalid) => return http_handler::write_invalid(req, &invalid),
+        };
+        if let Err(invalid) = http_handler::prepare_config(&mut config) {
+            return http_handler::write_invalid(req, &invalid);
         }
 
-        let config: storage::PwmConfig = serde_json::from_slice(&buffer.as_slice())?;
-
         info!("steps: {:?}", config.steps.clone());
         info!("interval: {:?}", config.interval.clone());
 
-        let mut steps = cloned_steps.lock().unwrap();
-        *steps = config.steps.clone();
-
-        let mut interval = cloned_interval.lock().unwrap();
-        *interval = config.interval;
-
         match storage::save_config(&config) {
             Result::Ok(_) => {
                 info!("config saved");
@@ -150,6 +405,18 @@ fn main() -> Result<()> {
             }
         }
 
+        // a live upload takes precedence over the active profile at next boot
+        if let Err(e) = profile::clear_active() {
+            error!("clear active profile error: {:?}", e);
+        }
+
+        *cloned_pwm_config.lock().unwrap() = config;
+        *cloned_transport.lock().unwrap() = pwm_loop::Transport::Start;
+        cloned_applied
+            .lock()
+            .unwrap()
+            .bump(revision::Origin::Upload, None);
+
         req.into_response(200, None, &[("Content-type", "text/plain; charset=UTF-8")])?
             .write_all("ok".as_bytes())?;
 
@@ -162,77 +429,3 @@ fn main() -> Result<()> {
 
     Ok(())
 }
-
-mod main_loop {
-    use std::{
-        sync::{Arc, Mutex},
-        thread::{self, JoinHandle},
-        time::Duration,
-    };
-
-    use esp_idf_svc::hal::{
-        gpio::{Output, OutputPin, PinDriver},
-        ledc::LedcDriver,
-    };
-    use log::info;
-
-    pub struct Pinner<'a, ReversePin: OutputPin> {
-        pub direction: PinDriver<'a, ReversePin, Output>,
-        pub led: LedcDriver<'a>,
-        pub output: LedcDriver<'a>,
-    }
-
-    pub fn new<ReversePin: OutputPin>(
-        mut pinner: Pinner<'static, ReversePin>,
-        interval: Arc<Mutex<u64>>,
-        steps: Arc<Mutex<Vec<i32>>>,
-    ) -> JoinHandle<()> {
-        thread::spawn(move || {
-            let mut index = 0;
-            let mut interval_: u64;
-            let mut duty: i32 = 0;
-
-            let max_duty = pinner.led.get_max_duty();
-            info!("max duty: {:?}", max_duty);
-
-            loop {
-                {
-                    interval_ = *interval.lock().unwrap();
-
-                    let steps_ = steps.lock().unwrap();
-
-                    if steps_.len() == 1 {
-                        duty = steps_[0];
-                    } else if steps_.len() > 1 {
-                        if index >= steps_.len() {
-                            index = 0;
-                        }
-                        duty = steps_[index];
-                    }
-                }
-
-                {
-                    if duty < 0 {
-                        duty = -duty;
-                        if pinner.direction.is_set_low() {
-                            pinner.direction.set_high().unwrap();
-                        }
-                    } else {
-                        if pinner.direction.is_set_high() {
-                            pinner.direction.set_low().unwrap();
-                        }
-                    }
-
-                    pinner.led.set_duty(duty.try_into().unwrap()).unwrap();
-                    pinner.output.set_duty(duty.try_into().unwrap()).unwrap();
-
-                    // info!("duty: {:?}", duty);
-
-                    index += 1;
-                }
-
-                thread::sleep(Duration::from_millis(interval_));
-            }
-        })
-    }
-}
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    adc::{self, AdcChannel},
+    input::Source,
+    thermo,
+};
+
+/// A thermistor in a voltage divider on an ADC1 pin,
+/// coefficients default to a common 10k NTC (B3950).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NtcConfig {
+    pub gpio: i32,
+    /// ohm of the fixed resistor of the divider
+    #[serde(rename = "seriesResistance", default = "default_series_resistance")]
+    pub series_resistance: f32,
+    /// millivolts across the divider
+    #[serde(default = "default_supply")]
+    pub supply: f32,
+    /// the thermistor is between the supply and the pin instead of the pin and ground
+    #[serde(rename = "highSide", default)]
+    pub high_side: bool,
+    #[serde(default = "default_a")]
+    pub a: f64,
+    #[serde(default = "default_b")]
+    pub b: f64,
+    #[serde(default = "default_c")]
+    pub c: f64,
+}
+
+fn default_series_resistance() -> f32 {
+    10000.0
+}
+
+fn default_supply() -> f32 {
+    3300.0
+}
+
+fn default_a() -> f64 {
+    1.009249522e-3
+}
+
+fn default_b() -> f64 {
+    2.378405444e-4
+}
+
+fn default_c() -> f64 {
+    2.019202697e-7
+}
+
+pub struct Ntc {
+    channel: AdcChannel,
+    config: NtcConfig,
+}
+
+pub fn new(config: &NtcConfig) -> Result<Ntc> {
+    Ok(Ntc {
+        channel: adc::new(config.gpio)?,
+        config: config.clone(),
+    })
+}
+
+impl Ntc {
+    /// In celsius.
+    pub fn read(&self) -> Result<f32> {
+        let millivolts = self.channel.read_millivolts()? as f32;
+        let config = &self.config;
+        let resistance = thermo::divider_resistance(
+            millivolts,
+            config.supply,
+            config.series_resistance,
+            config.high_side,
+        )
+        .map_err(|e| anyhow!("NTC on GPIO{}: {}", config.gpio, e))?;
+        thermo::steinhart_hart(resistance, config.a, config.b, config.c)
+            .map_err(|e| anyhow!("NTC on GPIO{}: {}", config.gpio, e))
+    }
+}
+
+impl Source for Ntc {
+    fn read(&mut self) -> Result<f32> {
+        Ntc::read(self)
+    }
+}
@@ -0,0 +1,314 @@
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    compose::Node,
+    input::{Source, SourceConfig},
+};
+
+/// Drives the duty to hold a reading at a setpoint, e.g. a fan holding a temperature.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pid {
+    /// the chip temperature by default
+    #[serde(default)]
+    pub source: SourceConfig,
+    /// evaluated over milliseconds since start, e.g. `{"type": "constant", "value": 45}`
+    pub setpoint: Node,
+    #[serde(flatten)]
+    pub gains: Gains,
+    /// seconds, time constant of the low pass on the derivative, 0 does not filter
+    #[serde(rename = "derivativeFilter", default)]
+    pub derivative_filter: f32,
+    #[serde(rename = "minPWM", default)]
+    pub min_pwm: f32,
+    #[serde(rename = "maxPWM", default = "default_max_pwm")]
+    pub max_pwm: f32,
+    /// the duty rises with a reading above the setpoint, e.g. a fan cooling,
+    /// instead of with a reading below it, e.g. a heater
+    #[serde(default)]
+    pub reverse: bool,
+    /// in milliseconds
+    #[serde(rename = "sampleInterval", default = "default_sample_interval")]
+    pub sample_interval: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Gains {
+    pub kp: f32,
+    #[serde(default)]
+    pub ki: f32,
+    #[serde(default)]
+    pub kd: f32,
+}
+
+impl Gains {
+    pub fn validate(&self) -> Result<()> {
+        if self.kp < 0.0 || self.ki < 0.0 || self.kd < 0.0 {
+            return Err(anyhow!("pid gains must not be negative"));
+        }
+        Ok(())
+    }
+}
+
+fn default_max_pwm() -> f32 {
+    255.0
+}
+
+fn default_sample_interval() -> u64 {
+    1000
+}
+
+impl Pid {
+    pub fn validate(&self) -> Result<()> {
+        self.source.validate()?;
+        self.setpoint.validate()?;
+        if self.max_pwm <= self.min_pwm {
+            return Err(anyhow!("pid maxPWM must be greater than minPWM"));
+        }
+        self.gains.validate()?;
+        if self.derivative_filter < 0.0 {
+            return Err(anyhow!("pid derivativeFilter must not be negative"));
+        }
+        if self.sample_interval == 0 {
+            return Err(anyhow!("pid sampleInterval must not be 0"));
+        }
+        Ok(())
+    }
+
+    /// Played while there is no reading, full cooling or no heating.
+    pub fn fail_safe(&self) -> f32 {
+        if self.reverse {
+            self.max_pwm
+        } else {
+            self.min_pwm
+        }
+    }
+}
+
+/// Integral and derivative history of a PID.
+#[derive(Debug, Default)]
+pub struct State {
+    integral: f32,
+    previous: Option<f32>,
+    derivative: f32,
+}
+
+impl State {
+    /// The duty after `seconds` since the previous step, within the output limits.
+    pub fn step(&mut self, pid: &Pid, setpoint: f32, measurement: f32, seconds: f32) -> f32 {
+        let sign = if pid.reverse { -1.0 } else { 1.0 };
+        let error = sign * (setpoint - measurement);
+        let Gains { kp, ki, kd } = pid.gains;
+
+        // on the measurement, so a step of the setpoint does not kick the output
+        if let Some(previous) = self.previous {
+            if seconds > 0.0 {
+                let raw = -sign * (measurement - previous) / seconds;
+                let alpha = seconds / (pid.derivative_filter + seconds);
+                self.derivative += (raw - self.derivative) * alpha;
+            }
+        }
+        self.previous = Some(measurement);
+
+        let p = kp * error;
+        let d = kd * self.derivative;
+
+        // anti-windup: stop integrating while saturated in the direction of the error
+        let integrated = self.integral + ki * error * seconds;
+        let unclamped = p + integrated + d;
+        let saturated =
+            (unclamped > pid.max_pwm && error > 0.0) || (unclamped < pid.min_pwm && error < 0.0);
+        if !saturated {
+            self.integral = integrated.clamp(pid.min_pwm, pid.max_pwm);
+        }
+
+        (p + self.integral + d).clamp(pid.min_pwm, pid.max_pwm)
+    }
+}
+
+/// Steps a PID every `sample_interval` with a reading of its source.
+#[derive(Debug, Default)]
+pub struct Controller {
+    state: State,
+    sampled_at: Option<Instant>,
+    output: Option<f32>,
+}
+
+impl Controller {
+    /// None until the first successful reading, or after a failed one.
+    pub fn update(&mut self, pid: &Pid, elapsed: u64, source: &mut dyn Source) -> Option<f32> {
+        let seconds = match self.sampled_at {
+            Some(at) if (at.elapsed().as_millis() as u64) < pid.sample_interval => {
+                return self.output;
+            }
+            Some(at) => at.elapsed().as_secs_f32(),
+            None => 0.0,
+        };
+
+        self.sampled_at = Some(Instant::now());
+        self.output = match source.read() {
+            Ok(measurement) => {
+                let setpoint = pid.setpoint.value_at(elapsed);
+                Some(self.state.step(pid, setpoint, measurement, seconds))
+            }
+            Err(e) => {
+                warn!("pid reading error: {:?}", e);
+                // the history is of no use once the reading returns
+                self.state = State::default();
+                None
+            }
+        };
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::mock::Scripted;
+
+    const DT: f32 = 0.1;
+    const AMBIENT: f32 = 25.0;
+
+    fn pid(kp: f32, ki: f32, kd: f32, reverse: bool) -> Pid {
+        Pid {
+            source: SourceConfig::default(),
+            setpoint: Node::Constant { value: 45.0 },
+            gains: Gains { kp, ki, kd },
+            derivative_filter: 0.0,
+            min_pwm: 0.0,
+            max_pwm: 255.0,
+            reverse,
+            sample_interval: 0,
+        }
+    }
+
+    /// A heater losing heat to the ambient with a time constant of 20 s,
+    /// settling 0.2 degree above it for each duty.
+    fn heater(temperature: f32, duty: f32) -> f32 {
+        temperature + ((AMBIENT - temperature) / 20.0 + 0.01 * duty) * DT
+    }
+
+    /// Something heating itself to 35 degree, cooled by a fan.
+    fn cooled(temperature: f32, duty: f32) -> f32 {
+        temperature + ((AMBIENT - temperature) / 20.0 + 0.5 - 0.002 * duty) * DT
+    }
+
+    fn run(
+        pid: &Pid,
+        state: &mut State,
+        plant: fn(f32, f32) -> f32,
+        temperature: &mut f32,
+        setpoint: f32,
+        seconds: u32,
+    ) -> Vec<(f32, f32)> {
+        (0..seconds * 10)
+            .map(|_| {
+                let duty = state.step(pid, setpoint, *temperature, DT);
+                *temperature = plant(*temperature, duty);
+                (*temperature, duty)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn heater_converges() {
+        for kd in [0.0, 2.0] {
+            let pid = pid(10.0, 1.0, kd, false);
+            let mut temperature = AMBIENT;
+            let trace = run(
+                &pid,
+                &mut State::default(),
+                heater,
+                &mut temperature,
+                45.0,
+                300,
+            );
+
+            let (temperature, duty) = *trace.last().unwrap();
+            assert!((temperature - 45.0).abs() < 0.05, "{}", temperature);
+            // 20 degree above the ambient takes a duty of 100
+            assert!((duty - 100.0).abs() < 1.0, "{}", duty);
+            let overshoot = trace.iter().map(|(t, _)| *t).fold(f32::MIN, f32::max);
+            assert!(overshoot < 47.0, "{}", overshoot);
+        }
+    }
+
+    #[test]
+    fn fan_converges_in_reverse() {
+        let pid = pid(10.0, 1.0, 0.0, true);
+        let mut temperature = 35.0;
+        let trace = run(
+            &pid,
+            &mut State::default(),
+            cooled,
+            &mut temperature,
+            30.0,
+            300,
+        );
+
+        let (temperature, duty) = *trace.last().unwrap();
+        assert!((temperature - 30.0).abs() < 0.05, "{}", temperature);
+        assert!((duty - 125.0).abs() < 1.0, "{}", duty);
+        assert!(trace.iter().all(|(t, _)| *t > 29.5));
+    }
+
+    #[test]
+    fn no_windup_at_saturation() {
+        let pid = pid(10.0, 1.0, 0.0, false);
+        let mut state = State::default();
+        let mut temperature = AMBIENT;
+
+        // out of reach, the heater tops out 51 degree above the ambient
+        let trace = run(&pid, &mut state, heater, &mut temperature, 90.0, 200);
+        assert!(trace[trace.len() - 100..]
+            .iter()
+            .all(|(_, duty)| *duty > 250.0));
+        // the integral stops where the output saturates, instead of growing for 200 s
+        let error = 90.0 - temperature;
+        assert!(
+            state.integral + pid.gains.kp * error <= 255.0 + pid.gains.ki * error * DT + 0.01,
+            "{}",
+            state.integral
+        );
+
+        let trace = run(&pid, &mut state, heater, &mut temperature, 45.0, 300);
+        // leaves the saturation at once
+        assert!(trace[0].1 < 255.0);
+        assert!(trace.iter().all(|(t, _)| *t > 40.0));
+        assert!((temperature - 45.0).abs() < 0.05, "{}", temperature);
+    }
+
+    #[test]
+    fn output_limits() {
+        let pid = Pid {
+            min_pwm: 20.0,
+            max_pwm: 200.0,
+            ..pid(100.0, 0.0, 0.0, false)
+        };
+        let mut state = State::default();
+        assert_eq!(state.step(&pid, 45.0, 0.0, DT), 200.0);
+        assert_eq!(state.step(&pid, 45.0, 90.0, DT), 20.0);
+    }
+
+    #[test]
+    fn fail_safe() {
+        assert_eq!(pid(1.0, 0.0, 0.0, false).fail_safe(), 0.0);
+        assert_eq!(pid(1.0, 0.0, 0.0, true).fail_safe(), 255.0);
+
+        let pid = pid(10.0, 1.0, 0.0, false);
+        let mut controller = Controller::default();
+        let mut source = Scripted::new(vec![Some(40.0), None, Some(45.0)]);
+        assert!(controller.update(&pid, 0, &mut source).is_some());
+        // the player plays `fail_safe` on None
+        assert_eq!(controller.update(&pid, 0, &mut source), None);
+        assert_eq!(controller.state.integral, 0.0);
+        assert_eq!(controller.state.previous, None);
+        assert_eq!(controller.update(&pid, 0, &mut source), Some(0.0));
+        // exhausted
+        assert_eq!(controller.update(&pid, 0, &mut source), None);
+    }
+}
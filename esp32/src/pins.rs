@@ -0,0 +1,91 @@
+//! GPIOs a config may take, checked before any driver is built on an unchecked pin number.
+
+use anyhow::{anyhow, Result};
+
+/// GPIO0 to GPIO21 of the ESP32-C3.
+const MAX_GPIO: i32 = 21;
+
+/// The SPI flash and its supply.
+const FLASH_GPIOS: [i32; 7] = [11, 12, 13, 14, 15, 16, 17];
+
+/// Direction, LED and output of the board, see `main`.
+#[cfg(feature = "esp-c3-32s")]
+const BOARD_GPIOS: [i32; 3] = [5, 4, 3];
+#[cfg(feature = "esp32-c3-supermini")]
+const BOARD_GPIOS: [i32; 3] = [0, 8, 3];
+
+/// `gpio` exists, is not wired to the flash and is not driven by the board.
+pub fn check(name: &str, gpio: i32) -> Result<()> {
+    if !(0..=MAX_GPIO).contains(&gpio) {
+        return Err(anyhow!(
+            "{} GPIO{} is not a pin, 0 to {}",
+            name,
+            gpio,
+            MAX_GPIO
+        ));
+    }
+    if FLASH_GPIOS.contains(&gpio) {
+        return Err(anyhow!("{} GPIO{} is wired to the flash", name, gpio));
+    }
+    if BOARD_GPIOS.contains(&gpio) {
+        return Err(anyhow!("{} GPIO{} is taken by the board", name, gpio));
+    }
+    Ok(())
+}
+
+/// Every pin is `check`ed and taken by one name only.
+pub fn check_all(pins: &[(String, i32)]) -> Result<()> {
+    for (i, (name, gpio)) in pins.iter().enumerate() {
+        check(name, *gpio)?;
+        if let Some((other, _)) = pins[..i].iter().find(|(_, other)| other == gpio) {
+            return Err(anyhow!("{} GPIO{} is taken by {}", name, gpio, other));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pins(pins: &[(&str, i32)]) -> Vec<(String, i32)> {
+        pins.iter()
+            .map(|(name, gpio)| (name.to_string(), *gpio))
+            .collect()
+    }
+
+    #[test]
+    fn range() {
+        assert!(check("sensor", 1).is_ok());
+        assert!(check("sensor", 21).is_ok());
+        assert!(check("sensor", -1).is_err());
+        assert!(check("sensor", 22).is_err());
+        assert!(check("sensor", 64).is_err());
+        assert!(check("sensor", i32::MAX).is_err());
+    }
+
+    #[test]
+    fn flash() {
+        for gpio in 11..=17 {
+            assert!(check("sensor", gpio).is_err());
+        }
+        assert!(check("sensor", 10).is_ok());
+        assert!(check("sensor", 18).is_ok());
+    }
+
+    #[test]
+    fn board() {
+        for gpio in BOARD_GPIOS {
+            assert!(check("sensor", gpio).is_err());
+        }
+    }
+
+    #[test]
+    fn clash() {
+        assert!(check_all(&pins(&[("sda", 6), ("scl", 7), ("ds18b20", 10)])).is_ok());
+        assert!(check_all(&pins(&[("sda", 6), ("scl", 6)])).is_err());
+        assert!(check_all(&pins(&[("sda", 6), ("scl", 7), ("ds18b20", 6)])).is_err());
+        assert!(check_all(&pins(&[("sda", 6), ("scl", 12)])).is_err());
+        assert!(check_all(&[]).is_ok());
+    }
+}
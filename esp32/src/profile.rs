@@ -0,0 +1,198 @@
+use std::fs;
+
+use anyhow::{anyhow, Ok, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::PwmConfig;
+
+/**
+ * SPIFFS has no directories, profiles are flat files named
+ * /spiffs/p_<name>.json
+ * and the object name (without the mount point) must fit in 32 bytes.
+ */
+static PROFILE_FILE_PREFIX: &str = "/spiffs/p_";
+static PROFILE_FILE_SUFFIX: &str = ".json";
+
+/**
+ * Diagram
+ * utf8 name of the active profile
+ */
+static ACTIVE_FILE_NAME: &str = "/spiffs/active";
+
+pub const MAX_NAME_LEN: usize = 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub config: PwmConfig,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProfileMeta {
+    pub name: String,
+    pub description: String,
+    pub steps: usize,
+    pub interval: u64,
+}
+
+impl From<&Profile> for ProfileMeta {
+    fn from(profile: &Profile) -> Self {
+        ProfileMeta {
+            name: profile.name.clone(),
+            description: profile.description.clone(),
+            steps: profile.config.steps.len(),
+            interval: profile.config.interval,
+        }
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(anyhow!(
+            "profile name must be 1 to {} characters long",
+            MAX_NAME_LEN
+        ));
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(anyhow!(
+            "profile name may only contain letters, digits, '_' and '-'"
+        ));
+    }
+    Ok(())
+}
+
+fn file_name(name: &str) -> String {
+    format!("{}{}{}", PROFILE_FILE_PREFIX, name, PROFILE_FILE_SUFFIX)
+}
+
+pub fn list() -> Result<Vec<ProfileMeta>> {
+    let (dir, prefix) = PROFILE_FILE_PREFIX.rsplit_once('/').unwrap();
+
+    let mut profiles = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+
+        let name = match file_name
+            .strip_prefix(prefix)
+            .and_then(|name| name.strip_suffix(PROFILE_FILE_SUFFIX))
+        {
+            Some(name) => name,
+            None => continue,
+        };
+
+        // one bad profile must not hide the others
+        match get(name) {
+            Result::Ok(Some(profile)) => profiles.push(ProfileMeta::from(&profile)),
+            Result::Ok(None) => {}
+            Err(e) => warn!("skipping profile {}: {:?}", name, e),
+        }
+    }
+
+    profiles.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(profiles)
+}
+
+/// An invalid profile file is removed like an invalid config file.
+pub fn get(name: &str) -> Result<Option<Profile>> {
+    validate_name(name)?;
+
+    let file_name = file_name(name);
+    if !fs::exists(&file_name)? {
+        return Ok(None);
+    }
+
+    match serde_json::from_slice(&fs::read(&file_name)?) {
+        Result::Ok(profile) => Ok(Some(profile)),
+        Err(e) => {
+            warn!("invalid profile file {}, removing it: {:?}", file_name, e);
+            fs::remove_file(&file_name)?;
+            Ok(None)
+        }
+    }
+}
+
+pub fn put(profile: &Profile) -> Result<()> {
+    validate_name(&profile.name)?;
+
+    fs::write(file_name(&profile.name), serde_json::to_vec(profile)?)?;
+
+    Ok(())
+}
+
+/// Returns false if there was no such profile.
+pub fn delete(name: &str) -> Result<bool> {
+    validate_name(name)?;
+
+    let file_name = file_name(name);
+    if !fs::exists(&file_name)? {
+        return Ok(false);
+    }
+
+    fs::remove_file(&file_name)?;
+
+    if get_active_name()?.as_deref() == Some(name) {
+        clear_active()?;
+    }
+
+    Ok(true)
+}
+
+pub fn get_active_name() -> Result<Option<String>> {
+    if !fs::exists(ACTIVE_FILE_NAME)? {
+        return Ok(None);
+    }
+
+    let name = fs::read_to_string(ACTIVE_FILE_NAME)?;
+    if validate_name(&name).is_err() {
+        fs::remove_file(ACTIVE_FILE_NAME)?;
+        return Ok(None);
+    }
+
+    Ok(Some(name))
+}
+
+/// The active profile is the one played at boot,
+/// it is no longer active once its file is gone.
+pub fn get_active() -> Result<Option<Profile>> {
+    let name = match get_active_name()? {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+
+    let profile = get(&name)?;
+    if profile.is_none() {
+        warn!("active profile {} not found", name);
+        clear_active()?;
+    }
+
+    Ok(profile)
+}
+
+pub fn set_active(name: &str) -> Result<()> {
+    validate_name(name)?;
+
+    if !fs::exists(file_name(name))? {
+        return Err(anyhow!("profile not found: {}", name));
+    }
+
+    fs::write(ACTIVE_FILE_NAME, name.as_bytes())?;
+
+    Ok(())
+}
+
+pub fn clear_active() -> Result<()> {
+    if fs::exists(ACTIVE_FILE_NAME)? {
+        fs::remove_file(ACTIVE_FILE_NAME)?;
+    }
+
+    Ok(())
+}
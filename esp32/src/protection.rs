@@ -0,0 +1,255 @@
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{current::CurrentConfig, input::SourceConfig};
+
+/**
+ * Diagram
+ * json of ProtectionConfig
+ */
+static PROTECTION_FILE_NAME: &str = "/spiffs/protection.json";
+
+/// Rules checked by the player every tick, whatever mode is playing.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProtectionConfig {
+    /// the chip temperature by default
+    #[serde(default)]
+    pub source: SourceConfig,
+    /// a reading at or above it raises the over-temperature alarm
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub critical: Option<f32>,
+    /// played while a thermal alarm is latched, the max duty by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duty: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub runaway: Option<RunawayConfig>,
+    /// cuts or derates the output on overcurrent, over any thermal duty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current: Option<CurrentConfig>,
+}
+
+/// A reading still rising after `duration` at full duty is a load the output can not handle.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RunawayConfig {
+    /// in seconds
+    #[serde(default = "default_duration")]
+    pub duration: u64,
+    /// least rise over `duration` that counts, against noise
+    #[serde(default = "default_rise")]
+    pub rise: f32,
+}
+
+fn default_duration() -> u64 {
+    120
+}
+
+fn default_rise() -> f32 {
+    1.0
+}
+
+impl ProtectionConfig {
+    pub fn validate(&self) -> Result<()> {
+        self.source.validate()?;
+        if let Some(runaway) = &self.runaway {
+            if runaway.duration == 0 {
+                return Err(anyhow!("runaway duration must not be 0"));
+            }
+            if runaway.rise <= 0.0 {
+                return Err(anyhow!("runaway rise must be positive"));
+            }
+        }
+        if let Some(current) = &self.current {
+            current.validate()?;
+        }
+        Ok(())
+    }
+
+    /// The source is only read for thermal rules.
+    pub fn thermal(&self) -> bool {
+        self.critical.is_some() || self.runaway.is_some()
+    }
+}
+
+/// At the top of the range, a config maxed at 255 of the curve editor counts as full
+/// like the steady on of `full_duty`.
+pub fn full_on(duty: u32, full_duty: u32) -> bool {
+    duty + 1 >= full_duty
+}
+
+/// Compares the reading at full duty against the one at the start of a window.
+#[derive(Debug, Default)]
+pub struct Runaway {
+    window: Option<(Instant, f32)>,
+}
+
+impl Runaway {
+    /// True once the reading rose by `rise` over a whole window at full duty.
+    pub fn update(
+        &mut self,
+        config: &RunawayConfig,
+        full: bool,
+        reading: f32,
+        now: Instant,
+    ) -> bool {
+        if !full {
+            self.window = None;
+            return false;
+        }
+
+        let (since, start) = *self.window.get_or_insert((now, reading));
+        if now - since < Duration::from_secs(config.duration) {
+            return false;
+        }
+
+        if reading - start >= config.rise {
+            self.window = None;
+            return true;
+        }
+        // the output keeps up, watch the next window
+        self.window = Some((now, reading));
+        false
+    }
+
+    pub fn reset(&mut self) {
+        self.window = None;
+    }
+}
+
+pub type Protection = Arc<Mutex<ProtectionConfig>>;
+
+pub fn get() -> Result<ProtectionConfig> {
+    if !fs::exists(PROTECTION_FILE_NAME)? {
+        return Ok(Default::default());
+    }
+
+    match serde_json::from_slice(&fs::read(PROTECTION_FILE_NAME)?) {
+        Ok(config) => Ok(config),
+        Err(e) => {
+            warn!("invalid protection file, removing it: {:?}", e);
+            fs::remove_file(PROTECTION_FILE_NAME)?;
+            Ok(Default::default())
+        }
+    }
+}
+
+pub fn save(config: &ProtectionConfig) -> Result<()> {
+    fs::write(PROTECTION_FILE_NAME, serde_json::to_vec(config)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    fn config() -> RunawayConfig {
+        RunawayConfig {
+            duration: 120,
+            rise: 1.0,
+        }
+    }
+
+    /// Feeds a reading every second, the seconds since start of the first alarm.
+    fn run(runaway: &mut Runaway, start: Instant, samples: &[(bool, f32, u64)]) -> Option<u64> {
+        let config = config();
+        let mut at = 0;
+        for (full, reading, seconds) in samples {
+            for _ in 0..*seconds {
+                if runaway.update(&config, *full, *reading, start + SECOND * at as u32) {
+                    return Some(at);
+                }
+                at += 1;
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn full_on_at_the_top() {
+        assert!(full_on(256, 256));
+        assert!(full_on(255, 256));
+        assert!(!full_on(254, 256));
+        assert!(!full_on(0, 256));
+    }
+
+    #[test]
+    fn trips_after_the_window() {
+        let mut runaway = Runaway::default();
+        let start = Instant::now();
+        // armed at 40, still rising a whole window later
+        let at = run(&mut runaway, start, &[(true, 40.0, 60), (true, 41.5, 100)]);
+        assert_eq!(at, Some(120));
+    }
+
+    #[test]
+    fn a_steady_reading_does_not_trip() {
+        let mut runaway = Runaway::default();
+        let start = Instant::now();
+        assert_eq!(
+            run(&mut runaway, start, &[(true, 40.0, 60), (true, 40.9, 600)]),
+            None
+        );
+    }
+
+    #[test]
+    fn not_armed_below_full_duty() {
+        let mut runaway = Runaway::default();
+        let start = Instant::now();
+        assert_eq!(
+            run(
+                &mut runaway,
+                start,
+                &[(false, 40.0, 60), (false, 80.0, 600)]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn a_drop_of_the_duty_starts_over() {
+        let mut runaway = Runaway::default();
+        let start = Instant::now();
+        let at = run(
+            &mut runaway,
+            start,
+            &[(true, 40.0, 100), (false, 41.5, 1), (true, 41.5, 200)],
+        );
+        // rearmed at 41.5, which holds
+        assert_eq!(at, None);
+
+        let mut runaway = Runaway::default();
+        let at = run(
+            &mut runaway,
+            start,
+            &[
+                (true, 40.0, 100),
+                (false, 40.0, 1),
+                (true, 40.0, 60),
+                (true, 42.0, 100),
+            ],
+        );
+        assert_eq!(at, Some(101 + 120));
+    }
+
+    #[test]
+    fn reset_disarms() {
+        let mut runaway = Runaway::default();
+        let start = Instant::now();
+        assert_eq!(run(&mut runaway, start, &[(true, 40.0, 100)]), None);
+        runaway.reset();
+        let config = config();
+        // a fresh window from here
+        assert!(!runaway.update(&config, true, 45.0, start + SECOND * 121));
+        assert!(!runaway.update(&config, true, 50.0, start + SECOND * 200));
+        assert!(runaway.update(&config, true, 50.0, start + SECOND * 241));
+    }
+}
@@ -6,6 +6,11 @@ use esp_idf_svc::hal::{
     units::{Hertz, KiloHertz},
 };
 
+/// Bits of the default resolution.
+const BITS: u32 = 8;
+/// Full on at the default resolution, LEDC holds the output high at `1 << bits`, not below it.
+pub const MAX_DUTY: u32 = 1 << BITS;
+
 pub fn new<'a, Timer, Channel>(
     timer: impl Peripheral<P = Timer> + 'a,
     channel: impl Peripheral<P = Channel> + 'a,
@@ -19,6 +24,7 @@ where
 {
     let mut config = TimerConfig::default();
     config.frequency = frequency.unwrap_or(Hertz(1000));
+    // see `BITS`
     config.resolution = resolution.unwrap_or(Resolution::Bits8);
 
     let timer_driver = LedcTimerDriver::new(timer, &config)?;
@@ -1,53 +1,374 @@
 use std::{
     sync::{Arc, Mutex},
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use anyhow::Result;
 use esp_idf_svc::hal::{
     gpio::{Output, OutputPin, PinDriver},
     ledc::LedcDriver,
 };
-use log::info;
+use log::{error, info};
+
+use crate::{
+    adc,
+    alarm::{Alarms, Kind},
+    angle,
+    autotune::{self, Autotune, Tuner},
+    calibration::{self, Calibrate, Sweep},
+    current::{Current, CurrentStatus, Overcurrent},
+    encoder::Encoder,
+    fan::{self, Fan, FanStatus, Stall},
+    input::{Elapsed, Remote, RemoteValues, Source, SourceConfig},
+    lookup::{self, Deadband, Sampler},
+    pid::Controller,
+    protection::{self, Protection, Runaway},
+    pwm,
+    sensor::{self, Readings, Registry},
+    storage::PwmConfig,
+    tach::Tach,
+};
 
 pub struct Pinner<'a, ReversePin: OutputPin> {
     pub direction: PinDriver<'a, ReversePin, Output>,
     pub led: LedcDriver<'a>,
     pub output: LedcDriver<'a>,
+    /// of the fan on the output, if it has one
+    pub tach: Option<Tach>,
+    /// on the shaft of the output, if it has one
+    pub encoder: Option<Encoder>,
+}
+
+/// Like a note of a sampler, `Start` and `Stop` are requests taken by the player.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// play from the first step again
+    Start,
+    Playing,
+    /// play the release segment, or stop right away if there is none
+    Stop,
+    Releasing,
+    Stopped,
+}
+
+/// Name of the internal temperature sensor in the sensor service.
+pub const CHIP_SENSOR: &str = "chip";
+/// The longest the player sleeps between two looks at the output, whatever the step interval.
+const TICK: Duration = Duration::from_millis(10);
+/// Of an ADC pin of a lookup or a PID, in milliseconds.
+const ADC_INTERVAL: u64 = 100;
+/// Of the current sense, in milliseconds, a fresh sample each tick for the trip curve.
+const CURRENT_INTERVAL: u64 = TICK.as_millis() as u64;
+
+/// Everything a lookup or a PID can take its reading from.
+#[derive(Clone)]
+pub struct Inputs {
+    pub readings: Readings,
+    pub remote_values: RemoteValues,
+    /// ADC pins are added to the sensor service, the player only reads cached samples
+    pub registry: Registry,
+}
+
+impl Inputs {
+    /// Millivolts of an ADC pin sampled every `interval` ms by the sensor service.
+    pub fn open_adc(&self, gpio: i32, interval: u64) -> Box<dyn Source> {
+        self.registry.add(adc::definition(gpio, interval));
+        Box::new(sensor::Cached::new(
+            Arc::clone(&self.readings),
+            &adc::sensor_name(gpio),
+        ))
+    }
+
+    pub fn open(&self, config: &SourceConfig) -> Result<Box<dyn Source>> {
+        Ok(match config {
+            SourceConfig::Time { period } => Box::new(Elapsed::new(*period)),
+            SourceConfig::Adc { gpio } => self.open_adc(*gpio, ADC_INTERVAL),
+            SourceConfig::Temperature => {
+                Box::new(sensor::Cached::new(Arc::clone(&self.readings), CHIP_SENSOR))
+            }
+            SourceConfig::Sensor { name } => {
+                Box::new(sensor::Cached::new(Arc::clone(&self.readings), name))
+            }
+            SourceConfig::Remote { name } => {
+                Box::new(Remote::new(Arc::clone(&self.remote_values), name))
+            }
+        })
+    }
+}
+
+/// State the player shares with the HTTP handlers, besides the config and the transport.
+pub struct Shared {
+    pub autotune: Autotune,
+    pub fan: Fan,
+    pub calibrate: Calibrate,
+    pub protection: Protection,
+    pub alarms: Alarms,
+    pub current: Current,
+}
+
+/// The source opened for a config, None if opening failed, so it is not retried every tick.
+type OpenedSource = Option<(SourceConfig, Option<Box<dyn Source>>)>;
+
+/// Opens the source of `config` unless it is open already, true if it was opened now.
+fn reopen(source: &mut OpenedSource, config: &SourceConfig, inputs: &Inputs) -> bool {
+    if source.as_ref().map(|(c, _)| c) == Some(config) {
+        return false;
+    }
+
+    let opened = match inputs.open(config) {
+        Ok(opened) => Some(opened),
+        Err(e) => {
+            error!("open source error: {:?}", e);
+            None
+        }
+    };
+    *source = Some((config.clone(), opened));
+    true
 }
 
-pub fn setup<ReversePin: OutputPin>(
+pub fn new<ReversePin: OutputPin>(
     mut pinner: Pinner<'static, ReversePin>,
-    interval: Arc<Mutex<u64>>,
-    steps: Arc<Mutex<Vec<i32>>>,
+    pwm_config: Arc<Mutex<PwmConfig>>,
+    transport: Arc<Mutex<Transport>>,
+    inputs: Inputs,
+    shared: Shared,
 ) -> JoinHandle<()> {
     thread::spawn(move || {
+        let Shared {
+            autotune,
+            fan,
+            calibrate,
+            protection,
+            alarms,
+            current,
+        } = shared;
+
         let mut index = 0;
-        let mut interval_: u64;
         let mut duty: i32 = 0;
+        // the next step is taken at, the output is watched every tick in between
+        let mut step_at = Instant::now();
 
         let max_duty = pinner.led.get_max_duty();
         info!("max duty: {:?}", max_duty);
 
+        let mut started_at = Instant::now();
+        // of the status led blinking
+        let booted_at = Instant::now();
+
+        let mut sampler = Sampler::default();
+        let mut deadband = Deadband::default();
+        let mut controller = Controller::default();
+        // takes over the pid while tuning
+        let mut tuner: Option<Tuner> = None;
+        let mut source: OpenedSource = None;
+        let mut stall = Stall::default();
+        // takes over the output while calibrating
+        let mut sweep: Option<Sweep> = None;
+        let mut guard: OpenedSource = None;
+        let mut runaway = Runaway::default();
+        // the current sense of the output by pin
+        let mut sense: Option<(i32, Box<dyn Source>)> = None;
+        let mut overcurrent = Overcurrent::default();
+        let mut peak: Option<f32> = None;
+
         loop {
             {
-                interval_ = *interval.lock().unwrap();
+                let config = pwm_config.lock().unwrap();
+                let mut transport_ = transport.lock().unwrap();
+
+                // a transport change is taken at once, a step every interval
+                let now = Instant::now();
+                if now >= step_at || matches!(*transport_, Transport::Start | Transport::Stop) {
+                    step_at = now + Duration::from_millis(config.interval);
+
+                    let steps_ = &config.steps;
+
+                    // intro: 0..loop_start, loop: loop_start..loop_end, release: loop_end..
+                    let loop_end = config.loop_end.unwrap_or(steps_.len()).min(steps_.len());
+                    let loop_start = config.loop_start.unwrap_or(0).min(loop_end);
+                    let has_release = config.compose.is_none()
+                        && config.waveform.is_none()
+                        && config.pid.is_none()
+                        && config.lookup.is_none()
+                        && config.angle.is_none()
+                        && loop_end < steps_.len();
+
+                    match *transport_ {
+                        Transport::Start => {
+                            index = 0;
+                            started_at = Instant::now();
+                            sampler = Sampler::default();
+                            deadband.reset();
+                            controller = Controller::default();
+                            if let Some(tuner) = tuner.as_mut() {
+                                tuner.fail("restarted");
+                            }
+                            source = None;
+                            *transport_ = Transport::Playing;
+                            info!("transport: playing");
+                        }
+                        Transport::Stop if has_release => {
+                            index = loop_end;
+                            *transport_ = Transport::Releasing;
+                            info!("transport: releasing");
+                        }
+                        Transport::Stop => {
+                            duty = 0;
+                            *transport_ = Transport::Stopped;
+                            info!("transport: stopped");
+                        }
+                        _ => {}
+                    }
 
-                let steps_ = steps.lock().unwrap();
+                    let elapsed = started_at.elapsed().as_millis() as u64;
 
-                if steps_.len() == 1 {
-                    duty = steps_[0];
-                } else if steps_.len() > 1 {
-                    if index >= steps_.len() {
-                        index = 0;
+                    {
+                        let mut session = autotune.lock().unwrap();
+                        if let Some(settings) = session.request.take() {
+                            if let Some(pid) = &config.pid {
+                                let setpoint = settings
+                                    .setpoint
+                                    .unwrap_or_else(|| pid.setpoint.value_at(elapsed));
+                                info!("autotune: started at {}", setpoint);
+                                tuner = Some(Tuner::new(&settings, pid, setpoint));
+                            } else {
+                                error!("autotune: no pid in the playing config");
+                                session.status = Some(autotune::Status::failed(
+                                    settings.setpoint,
+                                    "no pid in the playing config",
+                                ));
+                            }
+                        }
+                        if session.cancel {
+                            session.cancel = false;
+                            if let Some(tuner) = tuner.as_mut() {
+                                tuner.fail("cancelled");
+                            }
+                        }
+                    }
+
+                    match *transport_ {
+                        Transport::Playing => {
+                            if let Some(node) = &config.compose {
+                                duty = node.value_at(elapsed).round() as i32;
+                            } else if let Some(waveform) = &config.waveform {
+                                duty = waveform.value_at(elapsed).round() as i32;
+                            } else if let Some(pid) = &config.pid {
+                                if reopen(&mut source, &pid.source, &inputs) {
+                                    controller = Controller::default();
+                                }
+
+                                let output = match (source.as_mut(), tuner.as_mut()) {
+                                    (Some((_, Some(opened))), Some(tuner)) => {
+                                        Some(tuner.update(pid, opened.as_mut()))
+                                    }
+                                    (Some((_, Some(opened))), None) => {
+                                        controller.update(pid, elapsed, opened.as_mut())
+                                    }
+                                    (_, Some(tuner)) => {
+                                        tuner.fail("source is not open");
+                                        None
+                                    }
+                                    _ => None,
+                                };
+
+                                duty = match (output, config.safe_duty) {
+                                    (Some(output), _) => output.round() as i32,
+                                    (None, Some(safe_duty)) => safe_duty,
+                                    (None, None) => pid.fail_safe().round() as i32,
+                                };
+                            } else if let Some(lookup) = &config.lookup {
+                                if reopen(&mut source, &lookup.source, &inputs) {
+                                    sampler = Sampler::default();
+                                    deadband.reset();
+                                }
+
+                                let reading = match source.as_mut() {
+                                    Some((_, Some(opened))) => {
+                                        sampler.sample(lookup, opened.as_mut())
+                                    }
+                                    _ => None,
+                                };
+
+                                duty = match reading {
+                                    Some(x) => {
+                                        let mapped =
+                                            lookup::interpolate(steps_, lookup.min, lookup.max, x);
+                                        let ends = [
+                                            steps_.first().copied().unwrap_or(0) as f32,
+                                            steps_.last().copied().unwrap_or(0) as f32,
+                                        ];
+                                        deadband.update(mapped, lookup.deadband, ends).round()
+                                            as i32
+                                    }
+                                    // fail safe, the hot end of a fan curve by default
+                                    None => {
+                                        deadband.reset();
+                                        config
+                                            .safe_duty
+                                            .unwrap_or_else(|| steps_.last().copied().unwrap_or(0))
+                                    }
+                                };
+                            } else if let Some(angle_) = &config.angle {
+                                let position = pinner.encoder.as_ref().map(|e| e.position());
+                                duty = match position {
+                                    Some(position) if position.homed => {
+                                        angle::duty_at(steps_, position.angle + angle_.offset)
+                                            .round() as i32
+                                    }
+                                    Some(_) => angle_.homing_duty,
+                                    // no position to follow
+                                    None => 0,
+                                };
+                            } else if loop_end > 0 {
+                                if index >= loop_end {
+                                    // an empty loop holds the last intro step
+                                    index = if loop_start < loop_end {
+                                        loop_start
+                                    } else {
+                                        loop_end - 1
+                                    };
+                                }
+                                duty = steps_[index];
+                                index += 1;
+                            }
+                        }
+                        Transport::Releasing => {
+                            if index < steps_.len() {
+                                duty = steps_[index];
+                                index += 1;
+                            } else {
+                                // stopped is off, with or without a release
+                                duty = 0;
+                                *transport_ = Transport::Stopped;
+                                info!("transport: stopped");
+                            }
+                        }
+                        _ => {}
+                    }
+
+                    if let Some(tuner_) = tuner.as_mut() {
+                        if config.pid.is_none() {
+                            tuner_.fail("no pid in the playing config");
+                        } else if *transport_ != Transport::Playing {
+                            tuner_.fail("stopped");
+                        }
+
+                        let status = tuner_.status();
+                        if tuner_.finished() {
+                            info!("autotune: {:?}", status);
+                            tuner = None;
+                            // the integral of before the tuning is of no use
+                            controller = Controller::default();
+                        }
+                        autotune.lock().unwrap().status = Some(status);
                     }
-                    duty = steps_[index];
                 }
             }
 
             {
                 if duty < 0 {
-                    duty = -duty;
                     if pinner.direction.is_set_low() {
                         pinner.direction.set_high().unwrap();
                     }
@@ -57,15 +378,234 @@ pub fn setup<ReversePin: OutputPin>(
                     }
                 }
 
-                pinner.led.set_duty(duty.try_into().unwrap()).unwrap();
-                pinner.output.set_duty(duty.try_into().unwrap()).unwrap();
+                // computed waves are not bounded like uploaded steps
+                let mut duty_ = duty.unsigned_abs().min(max_duty);
 
-                // info!("duty: {:?}", duty);
+                // the highest duty the current sense allows
+                let mut limit: Option<u32> = None;
+                let rpm = pinner.tach.as_mut().and_then(|tach| tach.rpm());
+
+                {
+                    let mut session = calibrate.lock().unwrap();
+                    if let Some(settings) = session.request.take() {
+                        if pinner.tach.is_some() {
+                            info!("calibration: started");
+                            sweep = Some(Sweep::new(&settings));
+                        }
+                    }
+                    if session.cancel {
+                        session.cancel = false;
+                        if let Some(sweep) = sweep.as_mut() {
+                            sweep.fail("cancelled");
+                        }
+                    }
+
+                    if let Some(sweep_) = sweep.as_mut() {
+                        duty_ = sweep_.update(rpm, Instant::now()).min(max_duty);
+                        session.status = Some(sweep_.status());
+                        if sweep_.finished() {
+                            if let Some(calibration) = sweep_.calibration() {
+                                info!(
+                                    "calibration: start {}, stop {}",
+                                    calibration.start, calibration.stop
+                                );
+                                if let Err(e) = calibration::save(calibration) {
+                                    error!("calibration save error: {:?}", e);
+                                }
+                                session.calibration = Some(calibration.clone());
+                            }
+                            sweep = None;
+                        }
+                    } else if let Some(calibration) = &session.calibration {
+                        duty_ = calibration.map(duty_, max_duty);
+                        // a standing fan needs more to start than a turning one to keep turning
+                        if duty_ > 0 && rpm == Some(0.0) {
+                            duty_ = duty_.max(calibration.start.min(max_duty));
+                        }
+                    }
+                }
+
+                {
+                    let config = protection.lock().unwrap();
+                    let reading = if config.thermal() {
+                        reopen(&mut guard, &config.source, &inputs);
+                        match guard.as_mut() {
+                            Some((_, Some(opened))) => opened.read().ok(),
+                            _ => None,
+                        }
+                    } else {
+                        None
+                    };
+
+                    let mut alarms_ = alarms.lock().unwrap();
+                    match (config.critical, reading) {
+                        (Some(critical), Some(reading)) if reading >= critical => {
+                            alarms_.raise(
+                                Kind::OverTemperature,
+                                format!("reading {:.1} at or above {:.1}", reading, critical),
+                            );
+                        }
+                        _ => alarms_.clear(Kind::OverTemperature),
+                    }
+
+                    match (&config.runaway, reading) {
+                        // a latched runaway starts over once acknowledged
+                        (Some(runaway_config), Some(reading))
+                            if !alarms_.latched(Kind::ThermalRunaway) =>
+                        {
+                            if runaway.update(
+                                runaway_config,
+                                protection::full_on(duty_, max_duty.min(pwm::MAX_DUTY)),
+                                reading,
+                                Instant::now(),
+                            ) {
+                                alarms_.raise(
+                                    Kind::ThermalRunaway,
+                                    format!(
+                                        "reading rose to {:.1} over {} s at full duty",
+                                        reading, runaway_config.duration
+                                    ),
+                                );
+                            }
+                        }
+                        _ => runaway.reset(),
+                    }
+
+                    // held until acknowledged, even once the reading is back to normal
+                    if alarms_.latched(Kind::OverTemperature)
+                        || alarms_.latched(Kind::ThermalRunaway)
+                    {
+                        duty_ = config.duty.unwrap_or(max_duty).min(max_duty);
+                        if let Some(sweep) = sweep.as_mut() {
+                            sweep.fail("protection alarm");
+                        }
+                    }
+
+                    let mut amps = None;
+                    match &config.current {
+                        Some(current_config) => {
+                            if sense.as_ref().map(|(gpio, _)| *gpio) != Some(current_config.gpio) {
+                                let opened = inputs.open_adc(current_config.gpio, CURRENT_INTERVAL);
+                                sense = Some((current_config.gpio, opened));
+                                overcurrent.reset();
+                            }
+
+                            amps = sense
+                                .as_mut()
+                                .and_then(|(_, source)| source.read().ok())
+                                .map(|millivolts| current_config.amps(millivolts));
+
+                            match amps {
+                                // a latched trip starts over once acknowledged
+                                Some(amps) if !alarms_.latched(Kind::Overcurrent) => {
+                                    if let Some(trip) =
+                                        overcurrent.update(current_config, amps, Instant::now())
+                                    {
+                                        alarms_.raise(
+                                            Kind::Overcurrent,
+                                            format!(
+                                                "{:.2} A above {:.2} A for {} ms",
+                                                amps, trip.current, trip.duration
+                                            ),
+                                        );
+                                    }
+                                }
+                                Some(amps)
+                                    if current_config
+                                        .trips
+                                        .iter()
+                                        .all(|trip| amps <= trip.current) =>
+                                {
+                                    overcurrent.reset();
+                                    alarms_.clear(Kind::Overcurrent);
+                                }
+                                _ => overcurrent.reset(),
+                            }
 
-                index += 1;
+                            if alarms_.latched(Kind::Overcurrent) {
+                                limit = Some(current_config.limit());
+                                if let Some(sweep) = sweep.as_mut() {
+                                    sweep.fail("overcurrent");
+                                }
+                            }
+                        }
+                        None => {
+                            sense = None;
+                            overcurrent.reset();
+                        }
+                    }
+
+                    if let Some(amps) = amps {
+                        peak = Some(peak.map_or(amps, |peak: f32| peak.max(amps)));
+                    }
+                    *current.lock().unwrap() = CurrentStatus {
+                        current: amps,
+                        peak,
+                        tripped: limit.is_some(),
+                    };
+                }
+
+                // before the stall check, so a cut output does not count as stalled
+                if let Some(limit) = limit {
+                    duty_ = duty_.min(limit);
+                }
+
+                let mut status = FanStatus::default();
+                if let Some(tach) = pinner.tach.as_ref() {
+                    let mut alarms_ = alarms.lock().unwrap();
+                    if sweep.is_some() {
+                        // a sweep stops the fan on purpose
+                        stall = Stall::default();
+                    } else {
+                        if stall.state() == fan::State::Stalled
+                            && !alarms_.latched(Kind::FanStalled)
+                        {
+                            info!("fan stall acknowledged, kick-starting again");
+                            stall = Stall::default();
+                        }
+
+                        let was_stalled = stall.state() == fan::State::Stalled;
+                        if let Some(kick) = stall.update(tach.config(), duty_, rpm, Instant::now())
+                        {
+                            duty_ = kick.min(max_duty).min(limit.unwrap_or(max_duty));
+                        }
+
+                        if stall.state() == fan::State::Stalled && !was_stalled {
+                            alarms_.raise(
+                                Kind::FanStalled,
+                                format!("no revolutions after {} kick-starts", stall.kicks()),
+                            );
+                        } else if stall.state() == fan::State::Running {
+                            alarms_.clear(Kind::FanStalled);
+                        }
+                    }
+
+                    status.rpm = rpm;
+                    status.state = stall.state();
+                    status.kicks = stall.kicks();
+                    status.alarm = alarms_.latched(Kind::FanStalled);
+                }
+                status.duty = duty_;
+                *fan.lock().unwrap() = status;
+
+                // the status led blinks while an alarm is latched, instead of following the duty
+                let led_duty = if alarms.lock().unwrap().any() {
+                    if (booted_at.elapsed().as_millis() / 500) % 2 == 0 {
+                        max_duty
+                    } else {
+                        0
+                    }
+                } else {
+                    duty_
+                };
+                pinner.led.set_duty(led_duty).unwrap();
+                pinner.output.set_duty(duty_).unwrap();
+
+                // info!("duty: {:?}", duty);
             }
 
-            thread::sleep(Duration::from_millis(interval_));
+            // protection, the current sense and the status led do not wait for a long step
+            thread::sleep(TICK.min(step_at.saturating_duration_since(Instant::now())));
         }
     })
 }
@@ -0,0 +1,54 @@
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::sys::esp_timer_get_time;
+use serde::Serialize;
+
+/// Where the playing config came from.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Origin {
+    /// nothing was saved at boot
+    #[default]
+    Default,
+    /// the last uploaded config, read at boot
+    BootFile,
+    /// the active profile, read at boot
+    BootProfile,
+    /// `POST /pwm`
+    Upload,
+    /// `POST /profile/active`
+    Profile,
+    /// `PUT /pid` changed the gains of the playing config
+    PidGains,
+}
+
+/// Milliseconds since boot.
+pub fn uptime() -> u64 {
+    (unsafe { esp_timer_get_time() } / 1000) as u64
+}
+
+/// Metadata of the playing config, see `GET /pwm`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Revision {
+    /// counts every config applied since boot
+    pub generation: u32,
+    pub origin: Origin,
+    /// of a profile origin
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// milliseconds since boot
+    #[serde(rename = "appliedAt")]
+    pub applied_at: u64,
+}
+
+impl Revision {
+    /// A new config is playing.
+    pub fn bump(&mut self, origin: Origin, profile: Option<&str>) {
+        self.generation += 1;
+        self.origin = origin;
+        self.profile = profile.map(str::to_string);
+        self.applied_at = uptime();
+    }
+}
+
+pub type Applied = Arc<Mutex<Revision>>;
@@ -0,0 +1,407 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::{
+    filter::{Chain, FilterConfig},
+    input::Source,
+};
+
+/// The longest the service sleeps before it checks which sensor is due,
+/// it wakes up earlier for a sensor due sooner.
+const TICK: Duration = Duration::from_millis(100);
+/// Wait before opening a sensor again after it failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// A reading is stale after missing this many samples.
+const STALE_SAMPLES: u32 = 3;
+
+pub type Open = Box<dyn FnMut() -> Result<Box<dyn Source>> + Send>;
+
+pub struct Definition {
+    pub name: String,
+    /// in milliseconds
+    pub interval: u64,
+    /// applied in order to each raw sample
+    pub filters: Vec<FilterConfig>,
+    pub open: Open,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Reading {
+    /// filtered
+    pub value: Option<f32>,
+    pub raw: Option<f32>,
+    pub sampled_at: Option<Instant>,
+    /// of a remote value read through the sensor, see `Source::expires_at`
+    pub expires_at: Option<Instant>,
+    pub interval: u64,
+    pub error: Option<String>,
+}
+
+impl Reading {
+    /// The filtered value, unless it is stale.
+    pub fn fresh(&self) -> Option<f32> {
+        let sampled_at = self.sampled_at?;
+        if self.expires_at.is_some_and(|at| Instant::now() > at) {
+            return None;
+        }
+        let max_age = Duration::from_millis(self.interval) * STALE_SAMPLES;
+        if sampled_at.elapsed() > max_age.max(RETRY_INTERVAL) {
+            return None;
+        }
+        self.value
+    }
+}
+
+/// Latest reading of each sensor by name, shared by HTTP handlers and the player.
+pub type Readings = Arc<Mutex<HashMap<String, Reading>>>;
+
+#[derive(Serialize, Debug)]
+pub struct ReadingStatus {
+    pub value: Option<f32>,
+    pub raw: Option<f32>,
+    /// milliseconds since the last sample
+    pub age: Option<u64>,
+    pub stale: bool,
+    pub error: Option<String>,
+}
+
+impl From<&Reading> for ReadingStatus {
+    fn from(reading: &Reading) -> Self {
+        ReadingStatus {
+            value: reading.value,
+            raw: reading.raw,
+            age: reading.sampled_at.map(|at| at.elapsed().as_millis() as u64),
+            stale: reading.fresh().is_none(),
+            error: reading.error.clone(),
+        }
+    }
+}
+
+/// Sensors added after boot, e.g. the ADC pin of a lookup, taken by the service on its next tick.
+#[derive(Clone, Default)]
+pub struct Registry(Arc<Mutex<Vec<Definition>>>);
+
+impl Registry {
+    /// A sensor of the same name is sampled once, at the shorter interval of both.
+    pub fn add(&self, definition: Definition) {
+        self.0.lock().unwrap().push(definition);
+    }
+
+    fn take(&self) -> Vec<Definition> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+pub fn status(readings: &Readings) -> HashMap<String, ReadingStatus> {
+    readings
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(name, reading)| (name.clone(), ReadingStatus::from(reading)))
+        .collect()
+}
+
+struct Slot {
+    definition: Definition,
+    opened: Option<Box<dyn Source>>,
+    due_at: Instant,
+    /// of a measurement read once it is ready, see `Source::start`
+    started_at: Option<Instant>,
+    chain: Chain,
+}
+
+fn add(slots: &mut Vec<Slot>, readings: &Readings, definition: Definition) {
+    if let Some(slot) = slots
+        .iter_mut()
+        .find(|slot| slot.definition.name == definition.name)
+    {
+        if definition.interval < slot.definition.interval {
+            slot.definition.interval = definition.interval;
+            if let Some(reading) = readings.lock().unwrap().get_mut(&definition.name) {
+                reading.interval = definition.interval;
+            }
+        }
+        return;
+    }
+
+    info!("sensor {} added", definition.name);
+
+    readings.lock().unwrap().insert(
+        definition.name.clone(),
+        Reading {
+            interval: definition.interval,
+            ..Default::default()
+        },
+    );
+    slots.push(Slot {
+        chain: Chain::new(&definition.filters),
+        definition,
+        opened: None,
+        due_at: Instant::now(),
+        started_at: None,
+    });
+}
+
+/// Owns every sensor and samples each on its own interval,
+/// a sensor that fails to open or read is opened again later.
+/// Sensors of `registry` are added as they come.
+pub fn new(readings: Readings, registry: Registry, definitions: Vec<Definition>) -> JoinHandle<()> {
+    let mut slots = vec![];
+    for definition in definitions {
+        add(&mut slots, &readings, definition);
+    }
+
+    thread::spawn(move || loop {
+        for definition in registry.take() {
+            add(&mut slots, &readings, definition);
+        }
+
+        for slot in slots.iter_mut() {
+            if Instant::now() < slot.due_at {
+                continue;
+            }
+            sample(slot, &readings);
+        }
+
+        let next = slots.iter().map(|slot| slot.due_at).min();
+        let sleep = next.map_or(TICK, |next| {
+            next.saturating_duration_since(Instant::now()).min(TICK)
+        });
+        thread::sleep(sleep);
+    })
+}
+
+fn sample(slot: &mut Slot, readings: &Readings) {
+    let name = slot.definition.name.as_str();
+
+    if slot.opened.is_none() {
+        match (slot.definition.open)() {
+            Ok(opened) => {
+                info!("sensor {} opened", name);
+                slot.opened = Some(opened);
+            }
+            Err(e) => {
+                error!("sensor {} open error: {:?}", name, e);
+                slot.due_at = Instant::now() + RETRY_INTERVAL;
+                set_error(readings, name, e.to_string());
+                return;
+            }
+        }
+    }
+
+    let opened = slot.opened.as_mut().unwrap();
+
+    // a slow measurement is read on a later pass, the other sensors are sampled meanwhile
+    let started_at = match slot.started_at.take() {
+        Some(at) => at,
+        None => {
+            let now = Instant::now();
+            match opened.start() {
+                Ok(wait) if !wait.is_zero() => {
+                    slot.started_at = Some(now);
+                    slot.due_at = now + wait;
+                    return;
+                }
+                Ok(_) => now,
+                Err(e) => return fail(slot, readings, e),
+            }
+        }
+    };
+    let interval = Duration::from_millis(slot.definition.interval);
+    slot.due_at = (started_at + interval).max(Instant::now());
+
+    match opened.read() {
+        Ok(raw) => {
+            let mut readings = readings.lock().unwrap();
+            let reading = readings.entry(name.to_string()).or_default();
+
+            // the history of a stale reading would drag the new one back
+            let seconds = match reading.sampled_at {
+                Some(at) if reading.fresh().is_some() => at.elapsed().as_secs_f32(),
+                _ => {
+                    slot.chain.reset();
+                    0.0
+                }
+            };
+            let value = slot.chain.update(raw, seconds);
+
+            reading.raw = Some(raw);
+            reading.value = Some(value);
+            reading.sampled_at = Some(Instant::now());
+            reading.expires_at = opened.expires_at();
+            // sampled again without the expired value instead of staying stale until then
+            if let Some(at) = reading.expires_at {
+                slot.due_at = slot.due_at.min(at.max(Instant::now()));
+            }
+            reading.interval = slot.definition.interval;
+            reading.error = None;
+        }
+        Err(e) => fail(slot, readings, e),
+    }
+}
+
+fn fail(slot: &mut Slot, readings: &Readings, e: anyhow::Error) {
+    let name = slot.definition.name.as_str();
+    warn!("sensor {} read error, reopening it: {:?}", name, e);
+    slot.opened = None;
+    slot.started_at = None;
+    slot.due_at = Instant::now() + RETRY_INTERVAL;
+    set_error(readings, name, e.to_string());
+}
+
+fn set_error(readings: &Readings, name: &str, error: String) {
+    readings
+        .lock()
+        .unwrap()
+        .entry(name.to_string())
+        .or_default()
+        .error = Some(error);
+}
+
+/// The cached reading of a sensor as a lookup source.
+pub struct Cached {
+    readings: Readings,
+    name: String,
+    /// of the last reading read, an aggregate of an aggregate passes it on
+    expires_at: Option<Instant>,
+}
+
+impl Cached {
+    pub fn new(readings: Readings, name: &str) -> Self {
+        Cached {
+            readings,
+            name: name.to_string(),
+            expires_at: None,
+        }
+    }
+}
+
+impl Source for Cached {
+    fn read(&mut self) -> Result<f32> {
+        let readings = self.readings.lock().unwrap();
+        let reading = readings.get(&self.name);
+        let value = reading
+            .and_then(|reading| reading.fresh())
+            .ok_or_else(|| anyhow!("no fresh reading of sensor {}", self.name))?;
+        self.expires_at = reading.and_then(|reading| reading.expires_at);
+        Ok(value)
+    }
+
+    fn expires_at(&self) -> Option<Instant> {
+        self.expires_at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{self, mock::Scripted, Push, Remote, RemoteValues};
+
+    /// Converts for 750 ms like a DS18B20, then reads 21.5.
+    struct Converting {
+        started: bool,
+    }
+
+    impl Source for Converting {
+        fn read(&mut self) -> Result<f32> {
+            if !std::mem::take(&mut self.started) {
+                return Err(anyhow!("read before a conversion"));
+            }
+            Ok(21.5)
+        }
+
+        fn start(&mut self) -> Result<Duration> {
+            self.started = true;
+            Ok(Duration::from_millis(750))
+        }
+    }
+
+    fn slot(interval: u64, open: Open) -> (Vec<Slot>, Readings) {
+        let readings: Readings = Default::default();
+        let mut slots = vec![];
+        add(
+            &mut slots,
+            &readings,
+            Definition {
+                name: "case".to_string(),
+                interval,
+                filters: vec![],
+                open,
+            },
+        );
+        (slots, readings)
+    }
+
+    fn value(readings: &Readings) -> Option<f32> {
+        readings.lock().unwrap()["case"].value
+    }
+
+    #[test]
+    fn a_conversion_does_not_block() {
+        let (mut slots, readings) = slot(
+            1000,
+            Box::new(|| Ok(Box::new(Converting { started: false }) as Box<dyn Source>)),
+        );
+        let slot = &mut slots[0];
+
+        let before = Instant::now();
+        sample(slot, &readings);
+        assert!(before.elapsed() < Duration::from_millis(100));
+        assert_eq!(value(&readings), None);
+        assert!(slot.due_at >= before + Duration::from_millis(750));
+
+        sample(slot, &readings);
+        assert_eq!(value(&readings), Some(21.5));
+        // the interval counts from the start of the conversion
+        assert!(slot.due_at <= before + Duration::from_millis(1100));
+    }
+
+    #[test]
+    fn a_quick_source_is_read_at_once() {
+        let (mut slots, readings) = slot(
+            10,
+            Box::new(|| Ok(Box::new(Scripted::new(vec![Some(1.0), None])) as Box<dyn Source>)),
+        );
+        let slot = &mut slots[0];
+
+        sample(slot, &readings);
+        assert_eq!(value(&readings), Some(1.0));
+
+        sample(slot, &readings);
+        assert!(slot.opened.is_none());
+        assert!(readings.lock().unwrap()["case"].error.is_some());
+    }
+
+    #[test]
+    fn a_remote_value_expires_on_its_own() {
+        let values: RemoteValues = Default::default();
+        let push = Push {
+            name: "outside".to_string(),
+            value: 12.0,
+            ttl: Some(50),
+        };
+        input::push(&values, push).unwrap();
+        let (mut slots, readings) = slot(
+            60_000,
+            Box::new(move || {
+                Ok(Box::new(Remote::new(values.clone(), "outside")) as Box<dyn Source>)
+            }),
+        );
+
+        sample(&mut slots[0], &readings);
+        assert_eq!(readings.lock().unwrap()["case"].fresh(), Some(12.0));
+        assert!(slots[0].due_at <= Instant::now() + Duration::from_millis(50));
+        // long before 3 intervals of the sensor
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(readings.lock().unwrap()["case"].fresh(), None);
+        assert_eq!(Cached::new(readings, "case").read().ok(), None);
+    }
+}
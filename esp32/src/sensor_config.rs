@@ -0,0 +1,298 @@
+use std::{collections::HashSet, fs};
+
+use anyhow::{anyhow, Ok, Result};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    adc,
+    aggregate::{self, AggregateConfig},
+    angle::EncoderConfig,
+    ds18b20,
+    fan::TachConfig,
+    filter::FilterConfig,
+    i2c_sensor::{self, Bus, BusConfig},
+    input::{Source, SourceConfig},
+    ntc::{self, NtcConfig},
+    pins,
+    pwm_loop::{Inputs, CHIP_SENSOR},
+    sensor::Definition,
+};
+
+/**
+ * Diagram
+ * json of SensorsConfig, applied at boot
+ */
+static SENSORS_FILE_NAME: &str = "/spiffs/sensors.json";
+
+/// External sensors sampled by the sensor service besides the chip temperature,
+/// and the tachometer and the encoder read by the player.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SensorsConfig {
+    /// pins of I2C0, required by I2C sensors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub i2c: Option<BusConfig>,
+    #[serde(default)]
+    pub sensors: Vec<SensorConfig>,
+    /// filters of the chip temperature
+    #[serde(rename = "chipFilters", default = "default_chip_filters")]
+    pub chip_filters: Vec<FilterConfig>,
+    /// tachometer of the fan on the output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tach: Option<TachConfig>,
+    /// encoder on the shaft of the output, see `angle` of `POST /pwm`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<EncoderConfig>,
+}
+
+impl Default for SensorsConfig {
+    fn default() -> Self {
+        SensorsConfig {
+            i2c: None,
+            sensors: vec![],
+            chip_filters: default_chip_filters(),
+            tach: None,
+            encoder: None,
+        }
+    }
+}
+
+fn default_chip_filters() -> Vec<FilterConfig> {
+    vec![FilterConfig::Ema { weight: 0.3 }]
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SensorConfig {
+    pub name: String,
+    /// in milliseconds
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    /// applied in order to each raw sample, none keeps raw samples
+    #[serde(default)]
+    pub filters: Vec<FilterConfig>,
+    pub driver: Driver,
+}
+
+fn default_interval() -> u64 {
+    1000
+}
+
+/// Every driver besides `aggregate` reads celsius.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Driver {
+    /// the only DS18B20 on a 1-Wire bus
+    Ds18b20 { gpio: i32 },
+    /// a thermistor in a voltage divider on an ADC1 pin
+    Ntc(NtcConfig),
+    /// SHT30/31/35 on I2C
+    Sht3x {
+        #[serde(default = "default_sht3x_address")]
+        address: u8,
+    },
+    /// BMP280 or BME280 on I2C
+    Bmp280 {
+        #[serde(default = "default_bmp280_address")]
+        address: u8,
+    },
+    /// a virtual sensor over other sensors or remote values
+    Aggregate(AggregateConfig),
+}
+
+fn default_sht3x_address() -> u8 {
+    0x44
+}
+
+fn default_bmp280_address() -> u8 {
+    0x76
+}
+
+impl Driver {
+    fn uses_i2c(&self) -> bool {
+        matches!(self, Driver::Sht3x { .. } | Driver::Bmp280 { .. })
+    }
+}
+
+impl SensorsConfig {
+    pub fn validate(&self) -> Result<()> {
+        for filter in &self.chip_filters {
+            filter.validate()?;
+        }
+        if let Some(tach) = &self.tach {
+            tach.validate()?;
+        }
+        if let Some(encoder) = &self.encoder {
+            encoder.validate()?;
+        }
+
+        let mut gpios = vec![];
+        if let Some(tach) = &self.tach {
+            gpios.push(("tach".to_string(), tach.gpio));
+        }
+        if let Some(encoder) = &self.encoder {
+            gpios.extend(encoder.pins());
+        }
+        if let Some(i2c) = &self.i2c {
+            gpios.push(("i2c sda".to_string(), i2c.sda));
+            gpios.push(("i2c scl".to_string(), i2c.scl));
+        }
+
+        let mut names = HashSet::new();
+        for sensor in &self.sensors {
+            if sensor.name.is_empty() {
+                return Err(anyhow!("sensor needs a name"));
+            }
+            if sensor.name.starts_with(adc::SENSOR_PREFIX) {
+                return Err(anyhow!(
+                    "sensor names starting with {} are taken by ADC pins",
+                    adc::SENSOR_PREFIX
+                ));
+            }
+            if sensor.name == CHIP_SENSOR || !names.insert(sensor.name.as_str()) {
+                return Err(anyhow!("sensor name is taken: {}", sensor.name));
+            }
+            if sensor.interval == 0 {
+                return Err(anyhow!("interval of sensor {} must not be 0", sensor.name));
+            }
+            for filter in &sensor.filters {
+                filter
+                    .validate()
+                    .map_err(|e| anyhow!("sensor {}: {}", sensor.name, e))?;
+            }
+            if sensor.driver.uses_i2c() && self.i2c.is_none() {
+                return Err(anyhow!("sensor {} needs the i2c pins", sensor.name));
+            }
+            match &sensor.driver {
+                Driver::Ds18b20 { gpio } | Driver::Ntc(NtcConfig { gpio, .. }) => {
+                    gpios.push((format!("sensor {}", sensor.name), *gpio));
+                }
+                _ => {}
+            }
+        }
+        pins::check_all(&gpios)?;
+
+        for sensor in &self.sensors {
+            let aggregate = match &sensor.driver {
+                Driver::Aggregate(aggregate) => aggregate,
+                _ => continue,
+            };
+            aggregate
+                .validate()
+                .map_err(|e| anyhow!("sensor {}: {}", sensor.name, e))?;
+            for input in &aggregate.inputs {
+                match input {
+                    SourceConfig::Sensor { name } if *name == sensor.name => {
+                        return Err(anyhow!("sensor {} aggregates itself", sensor.name));
+                    }
+                    SourceConfig::Sensor { name }
+                        if name != CHIP_SENSOR
+                            && !name.starts_with(adc::SENSOR_PREFIX)
+                            && !names.contains(name.as_str()) =>
+                    {
+                        return Err(anyhow!(
+                            "sensor {} aggregates an unknown sensor {}",
+                            sensor.name,
+                            name
+                        ));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let aggregates: Vec<(&str, &AggregateConfig)> = self
+            .sensors
+            .iter()
+            .filter_map(|sensor| match &sensor.driver {
+                Driver::Aggregate(aggregate) => Some((sensor.name.as_str(), aggregate)),
+                _ => None,
+            })
+            .collect();
+        if let Some(cycle) = aggregate::cycle(&aggregates) {
+            return Err(anyhow!(
+                "sensors aggregate each other: {}",
+                cycle.join(" > ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn get() -> Result<SensorsConfig> {
+    if !fs::exists(SENSORS_FILE_NAME)? {
+        return Ok(Default::default());
+    }
+
+    let config: SensorsConfig = match serde_json::from_slice(&fs::read(SENSORS_FILE_NAME)?) {
+        Result::Ok(config) => config,
+        Err(e) => {
+            warn!("invalid sensors file, removing it: {:?}", e);
+            fs::remove_file(SENSORS_FILE_NAME)?;
+            return Ok(Default::default());
+        }
+    };
+
+    if let Err(e) = config.validate() {
+        warn!("invalid sensors file, ignoring it: {:?}", e);
+        return Ok(Default::default());
+    }
+
+    Ok(config)
+}
+
+pub fn save(config: &SensorsConfig) -> Result<()> {
+    config.validate()?;
+
+    fs::write(SENSORS_FILE_NAME, serde_json::to_vec(config)?)?;
+
+    Ok(())
+}
+
+/// Sensor service definitions of the external sensors,
+/// a sensor that can not be opened keeps retrying in the service.
+/// Aggregates read the other sensors and remote values of `inputs`.
+pub fn definitions(config: &SensorsConfig, inputs: &Inputs) -> Vec<Definition> {
+    let needs_bus = config.sensors.iter().any(|s| s.driver.uses_i2c());
+    let bus: Option<Bus> = match &config.i2c {
+        Some(bus_config) if needs_bus => match i2c_sensor::new_bus(bus_config) {
+            Result::Ok(bus) => Some(bus),
+            Err(e) => {
+                error!("i2c bus error: {:?}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    config
+        .sensors
+        .iter()
+        .map(|sensor| {
+            let driver = sensor.driver.clone();
+            let bus = bus.clone();
+            let inputs = inputs.clone();
+            Definition {
+                name: sensor.name.clone(),
+                interval: sensor.interval,
+                filters: sensor.filters.clone(),
+                open: Box::new(move || open(&driver, bus.as_ref(), &inputs)),
+            }
+        })
+        .collect()
+}
+
+fn open(driver: &Driver, bus: Option<&Bus>, inputs: &Inputs) -> Result<Box<dyn Source>> {
+    let bus = || {
+        bus.cloned()
+            .ok_or_else(|| anyhow!("i2c bus is not available"))
+    };
+
+    let source: Box<dyn Source> = match driver {
+        Driver::Ds18b20 { gpio } => Box::new(ds18b20::new(*gpio)?),
+        Driver::Ntc(config) => Box::new(ntc::new(config)?),
+        Driver::Sht3x { address } => Box::new(i2c_sensor::new_sht3x(bus()?, *address)?),
+        Driver::Bmp280 { address } => Box::new(i2c_sensor::new_bmp280(bus()?, *address)?),
+        Driver::Aggregate(config) => Box::new(aggregate::new(config, inputs)?),
+    };
+    Ok(source)
+}
@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Result};
+
+/// Same as the options form in index.html.
+#[derive(Debug, Clone)]
+pub struct StepOptions {
+    pub min_pwm: i32,
+    pub max_pwm: i32,
+    /// in milliseconds
+    pub duration: u64,
+    /// in milliseconds
+    pub interval: u64,
+    /// decimal places kept of each step
+    pub precision: u32,
+    pub max_steps: usize,
+    pub reverse: bool,
+}
+
+/// Port of the submit handler in index.html, so every client turns the same easing
+/// and options into the same steps.
+///
+/// Steps keep `precision` decimal places like the browser does,
+/// see `to_duty` for the integer steps the player takes.
+pub fn generate(ease: impl Fn(f64) -> f64, options: &StepOptions) -> Result<Vec<f64>> {
+    if options.max_pwm <= options.min_pwm {
+        return Err(anyhow!("Max PWM should be greater than Min PWM"));
+    }
+    // the browser loops until maxSteps on 0, and the last step must not overflow `elapsed`
+    if options.interval == 0 {
+        return Err(anyhow!("Interval should be greater than 0"));
+    }
+    if options.duration.checked_add(options.interval).is_none() {
+        return Err(anyhow!("Interval is too long for the duration"));
+    }
+
+    let min_pwm = options.min_pwm as f64;
+    let max_pwm = options.max_pwm as f64;
+    let pwm_range = max_pwm - min_pwm;
+    let decimals = 10f64.powi(options.precision as i32);
+
+    let mut elapsed = 0;
+
+    let mut steps = vec![];
+    while elapsed < options.duration && steps.len() < options.max_steps {
+        let progress = elapsed as f64 / options.duration as f64;
+
+        let mut pwm = min_pwm + (ease(progress) * pwm_range * decimals).floor() / decimals;
+        pwm = if pwm < 0.0 {
+            pwm.max(-max_pwm)
+        } else {
+            pwm.min(max_pwm)
+        };
+        if options.reverse {
+            pwm = (if pwm < 0.0 { -1.0 } else { 1.0 }) * max_pwm - pwm;
+        }
+
+        steps.push(pwm);
+        elapsed += options.interval;
+    }
+
+    Ok(steps)
+}
+
+/// Duty has no decimal places, anything kept by `precision` is floored.
+pub fn to_duty(steps: &[f64]) -> Vec<i32> {
+    steps.iter().map(|step| step.floor() as i32).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(min_pwm: i32, max_pwm: i32, duration: u64, interval: u64) -> StepOptions {
+        StepOptions {
+            min_pwm,
+            max_pwm,
+            duration,
+            interval,
+            precision: 0,
+            max_steps: 2000,
+            reverse: false,
+        }
+    }
+
+    // expected steps are outputs of the submit handler in index.html
+
+    #[test]
+    fn linear() {
+        let steps = generate(|p| p, &options(0, 255, 1000, 100)).unwrap();
+        assert_eq!(
+            steps,
+            [0.0, 25.0, 51.0, 76.0, 102.0, 127.0, 153.0, 178.0, 204.0, 229.0]
+        );
+    }
+
+    #[test]
+    fn reverse() {
+        let options = StepOptions {
+            reverse: true,
+            ..options(0, 255, 1000, 100)
+        };
+        let steps = generate(|p| p, &options).unwrap();
+        assert_eq!(
+            steps,
+            [255.0, 230.0, 204.0, 179.0, 153.0, 128.0, 102.0, 77.0, 51.0, 26.0]
+        );
+    }
+
+    #[test]
+    fn reverse_negative() {
+        let forward = generate(|p| 1.0 - 2.0 * p, &options(-100, 100, 1000, 250)).unwrap();
+        assert_eq!(forward, [100.0, 0.0, -100.0, -100.0]);
+
+        let options = StepOptions {
+            reverse: true,
+            ..options(-100, 100, 1000, 250)
+        };
+        let reversed = generate(|p| 1.0 - 2.0 * p, &options).unwrap();
+        assert_eq!(reversed, [0.0, 100.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn precision() {
+        let options = StepOptions {
+            precision: 2,
+            ..options(10, 100, 1000, 250)
+        };
+        let steps = generate(|p| p * p, &options).unwrap();
+        assert_eq!(steps, [10.0, 15.620000000000001, 32.5, 60.62]);
+        assert_eq!(to_duty(&steps), [10, 15, 32, 60]);
+    }
+
+    #[test]
+    fn max_steps() {
+        let options = StepOptions {
+            max_steps: 3,
+            ..options(0, 255, 1000, 100)
+        };
+        assert_eq!(generate(|p| p, &options).unwrap(), [0.0, 25.0, 51.0]);
+    }
+
+    #[test]
+    fn interval_edges() {
+        assert_eq!(
+            generate(|p| p, &options(0, 255, 1000, 1000)).unwrap(),
+            [0.0]
+        );
+        assert_eq!(
+            generate(|p| p, &options(0, 255, 1000, 5000)).unwrap(),
+            [0.0]
+        );
+        assert!(generate(|p| p, &options(0, 255, 0, 100))
+            .unwrap()
+            .is_empty());
+
+        assert!(generate(|p| p, &options(0, 255, 1000, 0)).is_err());
+        assert!(generate(|p| p, &options(0, 255, 1000, u64::MAX)).is_err());
+        assert!(generate(|p| p, &options(0, 255, u64::MAX - 1, 1))
+            .is_ok_and(|steps| steps.len() == 2000));
+    }
+
+    #[test]
+    fn min_above_max() {
+        assert!(generate(|p| p, &options(255, 0, 1000, 100)).is_err());
+    }
+
+    #[test]
+    fn duty_floors() {
+        assert_eq!(to_duty(&[1.9, -1.5, 0.0]), [1, -2, 0]);
+    }
+}
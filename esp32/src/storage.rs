@@ -1,67 +1,345 @@
-use std::fs;
+use std::{fmt, fs};
 
 use anyhow::{anyhow, Ok, Result};
 use esp_idf_svc::sys::{
-    esp_spiffs_check, esp_spiffs_info, esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register, ESP_OK,
+    esp_spiffs_check, esp_spiffs_info, esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register,
+    heap_caps_get_largest_free_block, ESP_OK, MALLOC_CAP_8BIT,
 };
 use log::{error, info, warn};
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 
-use crate::esp32;
+use crate::{
+    angle::Angle,
+    binary,
+    compose::Node,
+    curve, esp32,
+    lookup::Lookup,
+    pid::Pid,
+    pwm,
+    steps::StepOptions,
+    validation::{Invalid, TOO_MANY_STEPS},
+    waveform::Waveform,
+};
 
 static FS_BASE_PATH: &str = "/spiffs\0";
 
 /**
  * Diagram
- * interval_u64, pwm_i32 * n
+ * see `binary`, or interval_u64, pwm_i32 * n before it had a header
  */
 static CONFIG_FILE_NAME: &str = "/spiffs/config.bin";
 
-#[derive(Serialize, Deserialize, Debug)]
+/**
+ * Diagram
+ * json of PwmConfig without steps, everything besides steps and interval
+ */
+static CONFIG_EXTRA_FILE_NAME: &str = "/spiffs/config.json";
+
+/// 32k of steps in RAM, and twice that while an upload is parsed.
+pub const MAX_STEPS: usize = 8192;
+
+/// Steps that fit in the largest free block of the heap, up to `MAX_STEPS`.
+/// A step takes 4 bytes, and a growing Vec takes up to twice its length.
+pub fn step_limit() -> usize {
+    let free = unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_8BIT) };
+    (free / 8).min(MAX_STEPS)
+}
+
+/// Takes the steps one by one as they are parsed and stops at `step_limit`,
+/// instead of growing them until the heap runs out.
+fn deserialize_steps<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<i32>, D::Error> {
+    struct Steps;
+
+    impl<'de> Visitor<'de> for Steps {
+        type Value = Vec<i32>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an array of duties")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<i32>, A::Error> {
+            let limit = step_limit();
+            let mut steps = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(limit));
+            while let Some(step) = seq.next_element()? {
+                if steps.len() == limit {
+                    return Err(de::Error::custom(format!(
+                        "{}, {} fit in the free heap",
+                        TOO_MANY_STEPS, limit
+                    )));
+                }
+                steps.push(step);
+            }
+            Result::Ok(steps)
+        }
+    }
+
+    deserializer.deserialize_seq(Steps)
+}
+
+/// The curve editor inputs the steps were sampled from,
+/// field names follow the options form in index.html.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CurveSource {
+    /// svg path of the curve editor, its start path if omitted
+    #[serde(default = "default_path")]
+    pub path: String,
+    #[serde(rename = "minPWM")]
+    pub min_pwm: i32,
+    #[serde(rename = "maxPWM")]
+    pub max_pwm: i32,
+    /// in milliseconds
+    pub duration: u64,
+    #[serde(default)]
+    pub precision: u32,
+    #[serde(rename = "maxSteps", default = "default_max_steps")]
+    pub max_steps: usize,
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+impl CurveSource {
+    fn options(&self, interval: u64) -> StepOptions {
+        StepOptions {
+            min_pwm: self.min_pwm,
+            max_pwm: self.max_pwm,
+            duration: self.duration,
+            interval,
+            precision: self.precision,
+            max_steps: self.max_steps,
+            reverse: self.reverse,
+        }
+    }
+}
+
+fn default_path() -> String {
+    "M0, 100 C0, 100 50, 50 50, 50 C50, 50 100, 0 100, 0".to_string()
+}
+
+fn default_max_steps() -> usize {
+    2000
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PwmConfig {
+    /// can be omitted if `curve` is given, see `PwmConfig::fill_steps`
+    #[serde(default, deserialize_with = "deserialize_steps")]
     pub steps: Vec<i32>,
     pub interval: u64,
+    /// steps before it play once as an intro, defaults to 0
+    #[serde(rename = "loopStart", default, skip_serializing_if = "Option::is_none")]
+    pub loop_start: Option<usize>,
+    /// exclusive, steps from it play once on a stop request as a release, defaults to all steps
+    #[serde(rename = "loopEnd", default, skip_serializing_if = "Option::is_none")]
+    pub loop_end: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub curve: Option<CurveSource>,
+    /// played instead of steps if given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waveform: Option<Waveform>,
+    /// played instead of waveform and steps if given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compose: Option<Node>,
+    /// steps are looked up by a reading instead of played over time if given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lookup: Option<Lookup>,
+    /// the duty holds a reading at a setpoint instead of following steps if given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<Pid>,
+    /// steps are looked up by the angle of the encoder instead of played over time if given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub angle: Option<Angle>,
+    /// played by a lookup or a PID while there is no reading, e.g. of an expired remote value,
+    /// instead of the last step or the fail safe of the PID
+    #[serde(rename = "safeDuty", default, skip_serializing_if = "Option::is_none")]
+    pub safe_duty: Option<i32>,
 }
 
-pub fn get_config() -> Result<Option<PwmConfig>> {
-    if !fs::exists(CONFIG_FILE_NAME)? {
-        return Ok(None);
-    }
+impl PwmConfig {
+    /// Generates the steps on the device if the client only sent the curve.
+    pub fn fill_steps(&mut self) -> Result<()> {
+        if !self.steps.is_empty() {
+            return Ok(());
+        }
 
-    let config = fs::read(CONFIG_FILE_NAME)?;
-    if config.len() < 8 || (config.len() - 8) % 4 != 0 {
-        fs::remove_file(CONFIG_FILE_NAME)?;
-        return Ok(None);
+        if let Some(source) = &self.curve {
+            self.steps = curve::sample(&source.path, &source.options(self.interval))?;
+        }
+
+        Ok(())
     }
 
-    let interval = u64::from_be_bytes(config[0..8].try_into().unwrap());
-    let mut steps = vec![];
+    /// Rejects what the player can not play before it reaches the player or the flash.
+    pub fn validate(&self) -> Result<(), Invalid> {
+        if self.interval == 0 {
+            return Err(Invalid::unplayable("interval", "interval must not be 0"));
+        }
+
+        if self.steps.len() > MAX_STEPS {
+            return Err(Invalid::too_large(
+                "steps",
+                format!("more than {} steps", MAX_STEPS),
+            ));
+        }
+        // only compositions, waveforms and PIDs play without steps
+        let computed = self.compose.is_some() || self.waveform.is_some() || self.pid.is_some();
+        if self.steps.is_empty() && !computed {
+            return Err(Invalid::unplayable("steps", "steps must not be empty"));
+        }
+        for (index, step) in self.steps.iter().enumerate() {
+            check_duty(&format!("steps[{}]", index), *step as f32)?;
+        }
+
+        if let Some(waveform) = &self.waveform {
+            waveform
+                .validate()
+                .map_err(|e| Invalid::unplayable("waveform.dutyCycle", e))?;
+        }
+
+        if let Some(node) = &self.compose {
+            node.validate()
+                .map_err(|e| Invalid::unplayable("compose", e))?;
+        }
 
-    let mut index = 8;
-    loop {
-        if index >= config.len() {
-            break;
+        if let Some(pid) = &self.pid {
+            pid.validate().map_err(|e| Invalid::unplayable("pid", e))?;
+            check_duty("pid.minPWM", pid.min_pwm)?;
+            check_duty("pid.maxPWM", pid.max_pwm)?;
         }
 
-        steps.push(i32::from_be_bytes(
-            config[index..index + 4].try_into().unwrap(),
+        if let Some(lookup) = &self.lookup {
+            if lookup.max <= lookup.min {
+                return Err(Invalid::unplayable(
+                    "lookup.max",
+                    "lookup max must be greater than min",
+                ));
+            }
+            if lookup.hysteresis < 0.0 {
+                return Err(Invalid::unplayable(
+                    "lookup.hysteresis",
+                    "lookup hysteresis must not be negative",
+                ));
+            }
+            if lookup.deadband < 0.0 {
+                return Err(Invalid::unplayable(
+                    "lookup.deadband",
+                    "lookup deadband must not be negative",
+                ));
+            }
+            lookup
+                .source
+                .validate()
+                .map_err(|e| Invalid::unplayable("lookup.source", e))?;
+        }
+
+        if let Some(angle) = &self.angle {
+            check_duty("angle.homingDuty", angle.homing_duty as f32)?;
+        }
+        if let Some(safe_duty) = self.safe_duty {
+            check_duty("safeDuty", safe_duty as f32)?;
+        }
+
+        let loop_end = self.loop_end.unwrap_or(self.steps.len());
+        let loop_start = self.loop_start.unwrap_or(0);
+        if loop_end > self.steps.len() {
+            return Err(Invalid::unplayable(
+                "loopEnd",
+                "loopEnd is beyond the last step",
+            ));
+        }
+        if loop_start > loop_end {
+            return Err(Invalid::unplayable(
+                "loopStart",
+                "loopStart must not be after loopEnd",
+            ));
+        }
+        Result::Ok(())
+    }
+}
+
+/// A negative duty plays in reverse, see the direction pin of the player.
+fn check_duty(field: &str, duty: f32) -> Result<(), Invalid> {
+    if duty.abs() > pwm::MAX_DUTY as f32 {
+        return Err(Invalid::unplayable(
+            field,
+            format!("duty {} is above {}, full on", duty, pwm::MAX_DUTY),
         ));
+    }
+    Result::Ok(())
+}
+
+impl Default for PwmConfig {
+    fn default() -> Self {
+        PwmConfig {
+            steps: vec![],
+            interval: 100,
+            loop_start: None,
+            loop_end: None,
+            curve: None,
+            waveform: None,
+            compose: None,
+            lookup: None,
+            pid: None,
+            angle: None,
+            safe_duty: None,
+        }
+    }
+}
 
-        index += 4;
+pub fn get_config() -> Result<Option<PwmConfig>> {
+    if !fs::exists(CONFIG_FILE_NAME)? {
+        return Ok(None);
     }
 
-    Ok(Some(PwmConfig { steps, interval }))
+    let config = fs::read(CONFIG_FILE_NAME)?;
+    let decoded = if binary::is_encoded(&config) {
+        binary::read(config.as_slice(), MAX_STEPS).ok()
+    } else {
+        binary::read_legacy(&config)
+    };
+    let decoded = match decoded {
+        Some(decoded) => decoded,
+        None => {
+            fs::remove_file(CONFIG_FILE_NAME)?;
+            return Ok(None);
+        }
+    };
+
+    let mut config = get_config_extra()?;
+    config.steps = decoded.steps;
+    config.interval = decoded.interval;
+
+    Ok(Some(config))
 }
 
-pub fn save_config(config: &PwmConfig) -> Result<()> {
-    let mut buffer = vec![];
-    buffer.extend_from_slice(&config.interval.to_be_bytes());
+fn get_config_extra() -> Result<PwmConfig> {
+    if !fs::exists(CONFIG_EXTRA_FILE_NAME)? {
+        return Ok(Default::default());
+    }
 
-    for step in &config.steps {
-        buffer.extend_from_slice(&step.to_be_bytes());
+    match serde_json::from_slice(&fs::read(CONFIG_EXTRA_FILE_NAME)?) {
+        Result::Ok(config) => Ok(config),
+        Err(e) => {
+            warn!("invalid config extra file, removing it: {:?}", e);
+            fs::remove_file(CONFIG_EXTRA_FILE_NAME)?;
+            Ok(Default::default())
+        }
     }
+}
 
-    fs::write(CONFIG_FILE_NAME, &buffer)?;
+pub fn save_config(config: &PwmConfig) -> Result<()> {
+    fs::write(
+        CONFIG_FILE_NAME,
+        binary::encode(config.interval, &config.steps),
+    )?;
+
+    let extra = PwmConfig {
+        steps: vec![],
+        ..config.clone()
+    };
+    fs::write(CONFIG_EXTRA_FILE_NAME, serde_json::to_vec(&extra)?)?;
 
     Ok(())
 }
@@ -79,7 +357,7 @@ impl Default for SpiffsConfig {
         // spiffs_config.partition_label = "spiffs".as_ptr() as *const i8;
         spiffs_config.max_files = 2;
         spiffs_config.format_if_mount_failed = true;
-        
+
         SpiffsConfig(spiffs_config)
     }
 }
@@ -0,0 +1,118 @@
+use std::{
+    ffi::c_void,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use esp_idf_svc::sys::{
+    gpio_config, gpio_config_t, gpio_install_isr_service, gpio_int_type_t_GPIO_INTR_NEGEDGE,
+    gpio_isr_handler_add, gpio_isr_handler_remove, gpio_mode_t_GPIO_MODE_INPUT,
+    gpio_pulldown_t_GPIO_PULLDOWN_DISABLE, gpio_pullup_t_GPIO_PULLUP_ENABLE, ESP_ERR_INVALID_STATE,
+    ESP_OK,
+};
+use log::error;
+
+use crate::{
+    esp32,
+    fan::{self, TachConfig},
+    pins,
+};
+
+/// Pulses are counted over this long for a reading of revolutions.
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// The ESP32-C3 has no pulse counter, pulses of the open collector
+/// tachometer are counted by a GPIO interrupt on falling edges.
+pub struct Tach {
+    config: TachConfig,
+    pulses: *mut AtomicU32,
+    counted_since: Instant,
+    rpm: Option<f32>,
+}
+
+unsafe impl Send for Tach {}
+
+unsafe extern "C" fn on_pulse(arg: *mut c_void) {
+    (*(arg as *const AtomicU32)).fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn new(config: &TachConfig) -> Result<Tach> {
+    let gpio = config.gpio;
+    // the mask below is only defined for a pin of the chip
+    pins::check("tach", gpio)?;
+    unsafe {
+        let io_config = gpio_config_t {
+            pin_bit_mask: 1u64 << gpio,
+            mode: gpio_mode_t_GPIO_MODE_INPUT,
+            pull_up_en: gpio_pullup_t_GPIO_PULLUP_ENABLE,
+            pull_down_en: gpio_pulldown_t_GPIO_PULLDOWN_DISABLE,
+            intr_type: gpio_int_type_t_GPIO_INTR_NEGEDGE,
+            ..Default::default()
+        };
+        let res = gpio_config(&io_config);
+        if res != ESP_OK {
+            error!(
+                "Failed to config tach GPIO{}: {}",
+                gpio,
+                esp32::esp_err_to_str(res)
+            );
+            return Err(anyhow!("Failed to config tach GPIO{}", gpio));
+        }
+
+        // installed already if anything else subscribed to a pin
+        let res = gpio_install_isr_service(0);
+        if res != ESP_OK && res != ESP_ERR_INVALID_STATE {
+            error!(
+                "Failed to install GPIO ISR service: {}",
+                esp32::esp_err_to_str(res)
+            );
+            return Err(anyhow!("Failed to install GPIO ISR service"));
+        }
+
+        let pulses = Box::into_raw(Box::new(AtomicU32::new(0)));
+        let res = gpio_isr_handler_add(gpio, Some(on_pulse), pulses as *mut c_void);
+        if res != ESP_OK {
+            drop(Box::from_raw(pulses));
+            error!(
+                "Failed to add tach handler of GPIO{}: {}",
+                gpio,
+                esp32::esp_err_to_str(res)
+            );
+            return Err(anyhow!("Failed to add tach handler"));
+        }
+
+        Ok(Tach {
+            config: config.clone(),
+            pulses,
+            counted_since: Instant::now(),
+            rpm: None,
+        })
+    }
+}
+
+impl Tach {
+    /// Revolutions per minute of the last full window, None before the first one.
+    pub fn rpm(&mut self) -> Option<f32> {
+        let elapsed = self.counted_since.elapsed();
+        if elapsed >= WINDOW {
+            let pulses = unsafe { (*self.pulses).swap(0, Ordering::Relaxed) };
+            self.counted_since = Instant::now();
+            self.rpm = Some(fan::rpm(pulses, self.config.pulses_per_rev, elapsed));
+        }
+        self.rpm
+    }
+
+    pub fn config(&self) -> &TachConfig {
+        &self.config
+    }
+}
+
+impl Drop for Tach {
+    fn drop(&mut self) {
+        unsafe {
+            gpio_isr_handler_remove(self.config.gpio);
+            drop(Box::from_raw(self.pulses));
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use anyhow::{anyhow, Result};
+use esp_idf_svc::sys::{
+    soc_module_clk_t_SOC_MOD_CLK_XTAL, temperature_sensor_config_t, temperature_sensor_disable,
+    temperature_sensor_enable, temperature_sensor_get_celsius, temperature_sensor_handle_t,
+    temperature_sensor_install, temperature_sensor_uninstall, ESP_OK,
+};
+use log::error;
+
+use crate::{esp32, input::Source};
+
+/// The internal temperature sensor of the chip.
+pub struct TemperatureSensor(temperature_sensor_handle_t);
+
+unsafe impl Send for TemperatureSensor {}
+
+pub fn new() -> Result<TemperatureSensor> {
+    let mut handle: temperature_sensor_handle_t = std::ptr::null_mut();
+    unsafe {
+        let mut tsensor_config: temperature_sensor_config_t = Default::default();
+        tsensor_config.range_min = 10;
+        tsensor_config.range_max = 50;
+        tsensor_config.clk_src = soc_module_clk_t_SOC_MOD_CLK_XTAL;
+
+        let res = temperature_sensor_install(&tsensor_config, &mut handle);
+        if res != ESP_OK {
+            error!(
+                "Failed to install temperature sensor: {}",
+                esp32::esp_err_to_str(res)
+            );
+            return Err(anyhow!("Failed to install temperature sensor"));
+        }
+
+        let res = temperature_sensor_enable(handle);
+        if res != ESP_OK {
+            temperature_sensor_uninstall(handle);
+            error!(
+                "Failed to enable temperature sensor: {}",
+                esp32::esp_err_to_str(res)
+            );
+            return Err(anyhow!("Failed to enable temperature sensor"));
+        }
+    }
+
+    Ok(TemperatureSensor(handle))
+}
+
+impl TemperatureSensor {
+    /// In celsius.
+    pub fn read(&self) -> Result<f32> {
+        let mut celsius = 0f32;
+        let res = unsafe { temperature_sensor_get_celsius(self.0, &mut celsius) };
+        if res != ESP_OK {
+            return Err(anyhow!(
+                "Failed to read temperature sensor: {}",
+                esp32::esp_err_to_str(res)
+            ));
+        }
+        Ok(celsius)
+    }
+}
+
+impl Drop for TemperatureSensor {
+    fn drop(&mut self) {
+        unsafe {
+            temperature_sensor_disable(self.0);
+            temperature_sensor_uninstall(self.0);
+        }
+    }
+}
+
+impl Source for TemperatureSensor {
+    fn read(&mut self) -> Result<f32> {
+        TemperatureSensor::read(self)
+    }
+}
@@ -0,0 +1,247 @@
+//! Conversions from raw readings of external temperature sensors to celsius,
+//! free of hardware access.
+
+const KELVIN: f32 = 273.15;
+
+/// CRC-8/MAXIM (poly 0x31 reflected, init 0), used by 1-Wire ROM codes and scratchpads.
+pub fn crc8_maxim(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 0x01;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
+
+/// CRC-8 (poly 0x31, init 0xFF) of Sensirion sensors.
+pub fn crc8_sensirion(data: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Temperature of a DS18B20 scratchpad, the first two bytes are a little endian
+/// two's complement value in 1/16 degree.
+pub fn ds18b20_celsius(scratchpad: &[u8; 9]) -> Result<f32, &'static str> {
+    if crc8_maxim(&scratchpad[..8]) != scratchpad[8] {
+        return Err("scratchpad crc mismatch");
+    }
+    // a bus without pull-up reads all ones, which passes no crc, but all zeros does
+    if scratchpad.iter().all(|b| *b == 0) {
+        return Err("scratchpad is empty");
+    }
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]);
+    Ok(raw as f32 / 16.0)
+}
+
+/// Resistance of an NTC in a voltage divider,
+/// `high_side` if the thermistor is between the supply and the pin,
+/// otherwise between the pin and ground with the series resistor to the supply.
+pub fn divider_resistance(
+    millivolts: f32,
+    supply_millivolts: f32,
+    series_resistance: f32,
+    high_side: bool,
+) -> Result<f32, &'static str> {
+    if millivolts <= 0.0 || millivolts >= supply_millivolts {
+        return Err("divider voltage out of range, thermistor open or shorted");
+    }
+    let ratio = millivolts / (supply_millivolts - millivolts);
+    Ok(if high_side {
+        series_resistance / ratio
+    } else {
+        series_resistance * ratio
+    })
+}
+
+/// `1/T = a + b ln(R) + c ln(R)^3` with T in kelvin.
+pub fn steinhart_hart(resistance: f32, a: f64, b: f64, c: f64) -> Result<f32, &'static str> {
+    if resistance <= 0.0 {
+        return Err("resistance must be positive");
+    }
+    let ln = (resistance as f64).ln();
+    let inverse = a + b * ln + c * ln * ln * ln;
+    if inverse <= 0.0 {
+        return Err("coefficients give no temperature");
+    }
+    Ok((1.0 / inverse) as f32 - KELVIN)
+}
+
+/// Temperature word of a SHT3x measurement.
+pub fn sht3x_celsius(raw: u16) -> f32 {
+    -45.0 + 175.0 * raw as f32 / 65535.0
+}
+
+/// Temperature of a SHT3x measurement of 6 bytes,
+/// temperature, crc, humidity, crc.
+pub fn sht3x_measurement(data: &[u8; 6]) -> Result<f32, &'static str> {
+    if crc8_sensirion(&data[..2]) != data[2] {
+        return Err("temperature crc mismatch");
+    }
+    Ok(sht3x_celsius(u16::from_be_bytes([data[0], data[1]])))
+}
+
+/// Temperature trimming of a BMP280, registers 0x88 to 0x8D.
+#[derive(Debug, Clone, Copy)]
+pub struct Bmp280Calibration {
+    pub t1: u16,
+    pub t2: i16,
+    pub t3: i16,
+}
+
+impl Bmp280Calibration {
+    pub fn from_registers(registers: &[u8; 6]) -> Self {
+        Bmp280Calibration {
+            t1: u16::from_le_bytes([registers[0], registers[1]]),
+            t2: i16::from_le_bytes([registers[2], registers[3]]),
+            t3: i16::from_le_bytes([registers[4], registers[5]]),
+        }
+    }
+
+    /// The floating point compensation of the datasheet.
+    pub fn celsius(&self, adc_t: i32) -> f32 {
+        let adc_t = adc_t as f64;
+        let t1 = self.t1 as f64;
+        let var1 = (adc_t / 16384.0 - t1 / 1024.0) * self.t2 as f64;
+        let var2 = (adc_t / 131072.0 - t1 / 8192.0).powi(2) * self.t3 as f64;
+        ((var1 + var2) / 5120.0) as f32
+    }
+}
+
+/// 20 bit temperature of registers 0xFA to 0xFC.
+pub fn bmp280_adc_t(registers: &[u8; 3]) -> i32 {
+    ((registers[0] as i32) << 12) | ((registers[1] as i32) << 4) | ((registers[2] as i32) >> 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn crc8_maxim_check() {
+        assert_eq!(crc8_maxim(b"123456789"), 0xA1);
+        // ROM code of Maxim application note 27
+        assert_eq!(
+            crc8_maxim(&[0x02, 0x1C, 0xB8, 0x01, 0x00, 0x00, 0x00]),
+            0xA2
+        );
+        assert_eq!(crc8_maxim(&[]), 0x00);
+    }
+
+    #[test]
+    fn crc8_sensirion_check() {
+        // example of the SHT3x datasheet
+        assert_eq!(crc8_sensirion(&[0xBE, 0xEF]), 0x92);
+        assert_eq!(crc8_sensirion(b"123456789"), 0xF7);
+    }
+
+    fn scratchpad(raw: u16, crc: u8) -> [u8; 9] {
+        let [low, high] = raw.to_le_bytes();
+        [low, high, 0x4B, 0x46, 0x7F, 0xFF, 0x0C, 0x10, crc]
+    }
+
+    #[test]
+    fn ds18b20() {
+        // temperature/data relationship of the DS18B20 datasheet
+        assert_eq!(ds18b20_celsius(&scratchpad(0x07D0, 0xF4)), Ok(125.0));
+        assert_eq!(ds18b20_celsius(&scratchpad(0x0550, 0x1C)), Ok(85.0));
+        assert_eq!(ds18b20_celsius(&scratchpad(0x0191, 0x70)), Ok(25.0625));
+        assert_eq!(ds18b20_celsius(&scratchpad(0xFF5E, 0x6A)), Ok(-10.125));
+        assert_eq!(ds18b20_celsius(&scratchpad(0xFC90, 0x4F)), Ok(-55.0));
+    }
+
+    #[test]
+    fn ds18b20_bad_scratchpad() {
+        assert!(ds18b20_celsius(&scratchpad(0x0191, 0x71)).is_err());
+        // no pull-up
+        assert!(ds18b20_celsius(&[0xFF; 9]).is_err());
+        // shorted, passes the crc
+        assert!(ds18b20_celsius(&[0x00; 9]).is_err());
+    }
+
+    #[test]
+    fn steinhart_hart_10k() {
+        // a 10k thermistor at 0, 25 and 50 celsius
+        let (a, b, c) = (1.129148e-3, 2.34125e-4, 8.76741e-8);
+        close(steinhart_hart(32650.0, a, b, c).unwrap(), 0.0, 0.05);
+        close(steinhart_hart(10000.0, a, b, c).unwrap(), 25.0, 0.05);
+        close(steinhart_hart(3603.0, a, b, c).unwrap(), 50.0, 0.05);
+
+        assert!(steinhart_hart(0.0, a, b, c).is_err());
+        assert!(steinhart_hart(-1.0, a, b, c).is_err());
+        assert!(steinhart_hart(10000.0, -1.0, b, c).is_err());
+    }
+
+    #[test]
+    fn divider() {
+        close(
+            divider_resistance(1650.0, 3300.0, 10000.0, false).unwrap(),
+            10000.0,
+            0.1,
+        );
+        close(
+            divider_resistance(1100.0, 3300.0, 10000.0, false).unwrap(),
+            5000.0,
+            0.1,
+        );
+        close(
+            divider_resistance(1100.0, 3300.0, 10000.0, true).unwrap(),
+            20000.0,
+            0.1,
+        );
+
+        assert!(divider_resistance(0.0, 3300.0, 10000.0, false).is_err());
+        assert!(divider_resistance(3300.0, 3300.0, 10000.0, false).is_err());
+        assert!(divider_resistance(4000.0, 3300.0, 10000.0, true).is_err());
+    }
+
+    #[test]
+    fn sht3x() {
+        close(sht3x_celsius(0x0000), -45.0, 0.001);
+        close(sht3x_celsius(0xFFFF), 130.0, 0.001);
+        close(
+            sht3x_measurement(&[0x66, 0x66, 0x93, 0, 0, 0]).unwrap(),
+            25.0,
+            0.01,
+        );
+        assert!(sht3x_measurement(&[0x66, 0x66, 0x00, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn bmp280() {
+        // compensation example of the BMP280 datasheet
+        let calibration = Bmp280Calibration::from_registers(&[0x70, 0x6B, 0x43, 0x67, 0x18, 0xFC]);
+        assert_eq!(calibration.t1, 27504);
+        assert_eq!(calibration.t2, 26435);
+        assert_eq!(calibration.t3, -1000);
+
+        let adc_t = bmp280_adc_t(&[0x7E, 0xED, 0x00]);
+        assert_eq!(adc_t, 519888);
+        close(calibration.celsius(adc_t), 25.08, 0.01);
+    }
+}
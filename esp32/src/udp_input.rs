@@ -0,0 +1,43 @@
+use std::{
+    net::UdpSocket,
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{error, info, warn};
+
+use crate::input::{self, Push, RemoteValues};
+
+/// Longer datagrams are cut off, and fail to parse.
+const MAX_DATAGRAM: usize = 256;
+
+/// Takes pushes of remote values like `POST /input` does,
+/// one JSON object a datagram, e.g. `{"name": "cpu", "value": 52.5, "ttl": 10000}`.
+pub fn new(port: u16, remote_values: RemoteValues) -> Result<JoinHandle<()>> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    info!("udp input on port {}", port);
+
+    Ok(thread::spawn(move || {
+        let mut buffer = [0u8; MAX_DATAGRAM];
+        loop {
+            match socket.recv_from(&mut buffer) {
+                Ok((size, from)) => {
+                    let pushed = serde_json::from_slice::<Push>(&buffer[..size])
+                        .map_err(anyhow::Error::from)
+                        .and_then(|push| {
+                            push.validate()?;
+                            input::push(&remote_values, push)
+                        });
+                    if let Err(e) = pushed {
+                        warn!("invalid udp input from {}: {}", from, e);
+                    }
+                }
+                Err(e) => {
+                    error!("udp input error: {:?}", e);
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    }))
+}
@@ -0,0 +1,227 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, Read},
+};
+
+use serde::Serialize;
+
+use crate::compose;
+
+/// Starts the parse error of steps beyond `storage::step_limit`.
+pub const TOO_MANY_STEPS: &str = "too many steps";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Reason {
+    /// not the JSON of a config
+    Malformed,
+    /// more than the device can hold
+    TooLarge,
+    /// a config the hardware can not play
+    Unplayable,
+}
+
+impl Reason {
+    pub fn status(self) -> u16 {
+        match self {
+            Reason::Malformed => 400,
+            Reason::TooLarge => 413,
+            Reason::Unplayable => 422,
+        }
+    }
+}
+
+/// Error body of a rejected config, e.g.
+/// `{"error": "unplayable", "field": "steps[3]", "message": "duty 300 is above 256, full on"}`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Invalid {
+    pub error: Reason,
+    /// path of the failing field, `body` if it is unknown
+    pub field: String,
+    pub message: String,
+}
+
+impl Invalid {
+    pub fn unplayable(field: &str, message: impl Display) -> Self {
+        Invalid {
+            error: Reason::Unplayable,
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn too_large(field: &str, message: impl Display) -> Self {
+        Invalid {
+            error: Reason::TooLarge,
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// A binary upload, see `binary::read`.
+    pub fn malformed_at(field: &str, message: impl Display) -> Self {
+        Invalid {
+            error: Reason::Malformed,
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// serde only names a field when it is missing, unknown or duplicated.
+    pub fn malformed(e: &serde_json::Error) -> Self {
+        let message = e.to_string();
+        if message.starts_with(TOO_MANY_STEPS) {
+            return Invalid::too_large("steps", message);
+        }
+        let field = match message.split('`').nth(1) {
+            Some(field) if message.contains(" field `") => field.to_string(),
+            _ => "body".to_string(),
+        };
+        Invalid {
+            error: Reason::Malformed,
+            field,
+            message,
+        }
+    }
+
+    /// The same error of a config nested in `parent`, e.g. the config of a profile.
+    pub fn within(mut self, parent: &str) -> Self {
+        self.field = if self.field == "body" {
+            parent.to_string()
+        } else {
+            format!("{}.{}", parent, self.field)
+        };
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.error.status()
+    }
+}
+
+impl Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+/// Nesting of JSON objects and arrays in a body, enough for a composition of
+/// `compose::MAX_DEPTH` in the setpoint of a PID in a profile.
+pub const MAX_NESTING: usize = 2 * compose::MAX_DEPTH + 8;
+
+/// Scans JSON as it is read and fails once it nests deeper than `limit`,
+/// before serde recurses into it and overflows the stack of the HTTP task.
+pub struct DepthLimit<R> {
+    reader: R,
+    limit: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    exceeded: bool,
+}
+
+impl<R: Read> DepthLimit<R> {
+    pub fn new(reader: R, limit: usize) -> Self {
+        DepthLimit {
+            reader,
+            limit,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+            exceeded: false,
+        }
+    }
+
+    /// A failed read was cut at the limit.
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    pub fn too_deep(&self) -> Invalid {
+        Invalid::too_large("body", format!("nested deeper than {} levels", self.limit))
+    }
+}
+
+impl<R: Read> Read for DepthLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.reader.read(buf)?;
+        for &byte in &buf[..bytes_read] {
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => {
+                    self.depth += 1;
+                    if self.depth > self.limit {
+                        self.exceeded = true;
+                        return Err(io::Error::other("body too deep"));
+                    }
+                }
+                b'}' | b']' => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str, limit: usize) -> Result<serde_json::Value, bool> {
+        let mut reader = DepthLimit::new(json.as_bytes(), limit);
+        serde_json::from_reader(&mut reader).map_err(|_| reader.exceeded())
+    }
+
+    fn nested(depth: usize) -> String {
+        format!("{}{}", "[".repeat(depth), "]".repeat(depth))
+    }
+
+    #[test]
+    fn depth_limit() {
+        assert!(parse(&nested(4), 4).is_ok());
+        assert_eq!(parse(&nested(5), 4), Err(true));
+        assert_eq!(parse(&nested(10_000), MAX_NESTING), Err(true));
+        // siblings do not add up
+        assert!(parse(r#"{"a": [[1]], "b": [[2]], "c": {"d": [3]}}"#, 3).is_ok());
+    }
+
+    #[test]
+    fn depth_limit_strings() {
+        assert!(parse(r#"["[[[[", "\\", "\"{{{{"]"#, 1).is_ok());
+        assert_eq!(parse(r#"["\\", [[]]]"#, 2), Err(true));
+    }
+
+    #[test]
+    fn depth_limit_malformed() {
+        assert_eq!(parse("[1, }", 4), Err(false));
+    }
+
+    #[test]
+    fn malformed_field() {
+        let e =
+            serde_json::from_str::<serde_json::Map<String, serde_json::Value>>("[]").unwrap_err();
+        assert_eq!(Invalid::malformed(&e).field, "body");
+
+        #[derive(serde::Deserialize, Debug)]
+        #[allow(dead_code)]
+        struct Config {
+            interval: u64,
+        }
+        let e = serde_json::from_str::<Config>("{}").unwrap_err();
+        let invalid = Invalid::malformed(&e);
+        assert_eq!(invalid.field, "interval");
+        assert_eq!(invalid.status(), 400);
+        assert_eq!(invalid.within("config").field, "config.interval");
+    }
+}
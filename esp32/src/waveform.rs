@@ -0,0 +1,219 @@
+use std::f32::consts::PI;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Shape {
+    Sine,
+    Triangle,
+    Square,
+    Sawtooth,
+    /// a new random value every period, linearly interpolated in between
+    Noise,
+}
+
+/// A periodic wave computed at playback time instead of uploaded steps,
+/// `offset + amplitude * wave` where `wave` swings within -1..=1.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Waveform {
+    pub shape: Shape,
+    pub amplitude: f32,
+    #[serde(default)]
+    pub offset: f32,
+    /// in milliseconds
+    pub period: u64,
+    /// fraction of the period a square wave is high, 0..=1
+    #[serde(rename = "dutyCycle", default = "default_duty_cycle")]
+    pub duty_cycle: f32,
+    /// noise only
+    #[serde(default)]
+    pub seed: u32,
+}
+
+fn default_duty_cycle() -> f32 {
+    0.5
+}
+
+impl Waveform {
+    pub fn validate(&self) -> Result<()> {
+        // NaN is not contained either
+        if !(0.0..=1.0).contains(&self.duty_cycle) {
+            return Err(anyhow!(
+                "waveform dutyCycle {} is not within 0 to 1",
+                self.duty_cycle
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn value_at(&self, elapsed: u64) -> f32 {
+        self.offset + self.amplitude * self.wave(elapsed)
+    }
+
+    fn wave(&self, elapsed: u64) -> f32 {
+        if self.period == 0 {
+            return 0.0;
+        }
+
+        let cycle = elapsed / self.period;
+        let phase = (elapsed % self.period) as f32 / self.period as f32;
+
+        match self.shape {
+            Shape::Sine => (2.0 * PI * phase).sin(),
+            Shape::Triangle => {
+                if phase < 0.5 {
+                    4.0 * phase - 1.0
+                } else {
+                    3.0 - 4.0 * phase
+                }
+            }
+            Shape::Square => {
+                if phase < self.duty_cycle {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Shape::Sawtooth => 2.0 * phase - 1.0,
+            Shape::Noise => {
+                let from = noise(self.seed, cycle);
+                let to = noise(self.seed, cycle + 1);
+                from + (to - from) * phase
+            }
+        }
+    }
+}
+
+/// Stateless so the same seed always replays the same wave, -1..=1.
+fn noise(seed: u32, n: u64) -> f32 {
+    // splitmix64
+    let mut z = (((seed as u64) << 32) ^ n).wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+
+    (z >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: u64 = 1000;
+
+    fn waveform(shape: Shape) -> Waveform {
+        Waveform {
+            shape,
+            amplitude: 1.0,
+            offset: 0.0,
+            period: PERIOD,
+            duty_cycle: 0.5,
+            seed: 0,
+        }
+    }
+
+    /// The wave at the start, a quarter, half way, just before the end and at the wrap.
+    fn shape(waveform: &Waveform) -> [f32; 5] {
+        [0, 250, 500, PERIOD - 1, PERIOD].map(|at| waveform.value_at(at))
+    }
+
+    fn close(a: [f32; 5], b: [f32; 5]) -> bool {
+        a.iter().zip(b).all(|(a, b)| (a - b).abs() < 0.01)
+    }
+
+    #[test]
+    fn sine() {
+        let shape = shape(&waveform(Shape::Sine));
+        assert!(close(shape, [0.0, 1.0, 0.0, 0.0, 0.0]), "{:?}", shape);
+    }
+
+    #[test]
+    fn triangle() {
+        let shape = shape(&waveform(Shape::Triangle));
+        assert!(close(shape, [-1.0, 0.0, 1.0, -1.0, -1.0]), "{:?}", shape);
+    }
+
+    #[test]
+    fn square() {
+        assert_eq!(shape(&waveform(Shape::Square)), [1.0, 1.0, -1.0, -1.0, 1.0]);
+        let narrow = Waveform {
+            duty_cycle: 0.1,
+            ..waveform(Shape::Square)
+        };
+        assert_eq!(shape(&narrow), [1.0, -1.0, -1.0, -1.0, 1.0]);
+        let off = Waveform {
+            duty_cycle: 0.0,
+            ..waveform(Shape::Square)
+        };
+        assert_eq!(shape(&off), [-1.0; 5]);
+        let on = Waveform {
+            duty_cycle: 1.0,
+            ..waveform(Shape::Square)
+        };
+        assert_eq!(shape(&on), [1.0; 5]);
+    }
+
+    #[test]
+    fn sawtooth() {
+        let shape = shape(&waveform(Shape::Sawtooth));
+        assert!(close(shape, [-1.0, -0.5, 0.0, 1.0, -1.0]), "{:?}", shape);
+    }
+
+    #[test]
+    fn noise_is_continuous_at_the_wrap() {
+        let noise = waveform(Shape::Noise);
+        let [start, _, _, end, wrap] = shape(&noise);
+        assert_ne!(start, wrap);
+        assert!((end - wrap).abs() < 0.01);
+        for at in (0..10 * PERIOD).step_by(7) {
+            assert!((-1.0..=1.0).contains(&noise.value_at(at)));
+        }
+    }
+
+    #[test]
+    fn noise_replays_its_seed() {
+        let noise = waveform(Shape::Noise);
+        assert_eq!(shape(&noise), shape(&noise.clone()));
+        let other = Waveform {
+            seed: 1,
+            ..waveform(Shape::Noise)
+        };
+        assert_ne!(shape(&noise), shape(&other));
+    }
+
+    #[test]
+    fn offset_and_amplitude() {
+        let waveform = Waveform {
+            amplitude: 50.0,
+            offset: 100.0,
+            ..waveform(Shape::Square)
+        };
+        assert_eq!(waveform.value_at(0), 150.0);
+        assert_eq!(waveform.value_at(500), 50.0);
+        let flat = Waveform {
+            period: 0,
+            ..waveform
+        };
+        assert_eq!(flat.value_at(500), 100.0);
+    }
+
+    #[test]
+    fn duty_cycle() {
+        for duty_cycle in [0.0, 0.5, 1.0] {
+            let waveform = Waveform {
+                duty_cycle,
+                ..waveform(Shape::Square)
+            };
+            assert!(waveform.validate().is_ok());
+        }
+        for duty_cycle in [-0.1, 1.1, f32::NAN, f32::INFINITY] {
+            let waveform = Waveform {
+                duty_cycle,
+                ..waveform(Shape::Square)
+            };
+            assert!(waveform.validate().is_err());
+        }
+    }
+}